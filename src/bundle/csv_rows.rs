@@ -0,0 +1,56 @@
+use anyhow::Result;
+
+use crate::bundle::fields::struct_fields;
+use crate::bundle::*;
+use crate::utils::*;
+
+/// Rows as CSV records with a header of the row fields in declaration order
+pub(crate) struct CsvEncoder {
+    output: csv::Writer<HashingWriter<Box<dyn FinishWrite>>>,
+    fields: Vec<&'static str>,
+}
+
+impl CsvEncoder {
+    /// Writes the header, fields in [`BundleRow::SECRET_FIELDS`] are dropped unless `secret_fields` is set
+    pub(crate) fn new<R: BundleRow>(
+        output: HashingWriter<Box<dyn FinishWrite>>,
+        secret_fields: bool,
+    ) -> Result<Self> {
+        let fields = struct_fields::<R>()?
+            .iter()
+            .copied()
+            .filter(|field| secret_fields || !R::SECRET_FIELDS.contains(field))
+            .collect::<Vec<_>>();
+
+        let mut output = csv::Writer::from_writer(output);
+        output.write_record(&fields)?;
+
+        Ok(Self { output, fields })
+    }
+}
+
+impl<R: BundleRow> RowEncoder<R> for CsvEncoder {
+    fn write(&mut self, row: &R) -> Result<()> {
+        let row = match serde_json::to_value(row)? {
+            serde_json::Value::Object(row) => row,
+            _ => anyhow::bail!("{} rows are not structs", R::ENTITY),
+        };
+
+        for field in &self.fields {
+            // Nested values, e.g. transaction messages, stay JSON in a single cell
+            let cell = match row.get(*field) {
+                None | Some(serde_json::Value::Null) => String::new(),
+                Some(serde_json::Value::String(value)) => value.clone(),
+                Some(value) => value.to_string(),
+            };
+            self.output.write_field(cell)?;
+        }
+        self.output.write_record(None::<&[u8]>)?;
+
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<HashingWriter<Box<dyn FinishWrite>>> {
+        Ok(self.output.into_inner().map_err(|e| e.into_error())?)
+    }
+}
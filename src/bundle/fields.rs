@@ -0,0 +1,39 @@
+use anyhow::Result;
+use serde::de::{self, Deserializer, Visitor};
+use serde::forward_to_deserialize_any;
+
+use crate::bundle::*;
+
+/// Field names of `R` in declaration order, as its `Deserialize` implementation reports them
+pub(crate) fn struct_fields<R: BundleRow>() -> Result<&'static [&'static str]> {
+    let mut fields = None;
+    // Fails once the fields are captured, nothing is deserialized
+    let _ = R::deserialize(FieldNames(&mut fields));
+    fields.ok_or_else(|| anyhow::anyhow!("{} rows are not structs", R::ENTITY))
+}
+
+struct FieldNames<'a>(&'a mut Option<&'static [&'static str]>);
+
+impl<'de> Deserializer<'de> for FieldNames<'_> {
+    type Error = de::value::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+        Err(de::Error::custom("not a struct"))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        _visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        *self.0 = Some(fields);
+        Err(de::Error::custom("fields captured"))
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map enum identifier ignored_any
+    }
+}
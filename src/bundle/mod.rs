@@ -0,0 +1,58 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::models::*;
+
+pub use self::reader::*;
+pub use self::writer::*;
+
+mod csv_rows;
+mod fields;
+mod parquet_rows;
+mod reader;
+mod writer;
+
+/// Row stored in the bundle file of its entity
+pub trait BundleRow: Serialize + DeserializeOwned + Send + Sync {
+    const ENTITY: Entity;
+
+    /// Fields with secrets, files with them are encrypted when a transport passphrase is given
+    const SECRET_FIELDS: &'static [&'static str] = &[];
+
+    /// Whether the fields named by the comma separated `key` hold the comma separated `id`,
+    /// as reported by [`Error::DuplicateRow`](crate::Error::DuplicateRow)
+    fn has_key(&self, key: &str, id: &str) -> bool {
+        let fields = match serde_json::to_value(self) {
+            Ok(serde_json::Value::Object(fields)) => fields,
+            _ => return false,
+        };
+        let values = key
+            .split(", ")
+            .map(|column| match fields.get(column)? {
+                serde_json::Value::String(value) => Some(value.clone()),
+                serde_json::Value::Null => None,
+                value => Some(value.to_string()),
+            })
+            .collect::<Option<Vec<_>>>();
+        values.is_some_and(|values| values.join(", ") == id)
+    }
+}
+
+impl BundleRow for AddressDb {
+    const ENTITY: Entity = Entity::Addresses;
+
+    // Private keys are exported decrypted
+    const SECRET_FIELDS: &'static [&'static str] = &["private_key"];
+}
+
+impl BundleRow for TransactionDb {
+    const ENTITY: Entity = Entity::Transactions;
+}
+
+impl BundleRow for TokenOwnerDb {
+    const ENTITY: Entity = Entity::TokenOwners;
+}
+
+impl BundleRow for TokenTransactionDb {
+    const ENTITY: Entity = Entity::TokenTransactions;
+}
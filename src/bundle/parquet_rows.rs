@@ -0,0 +1,599 @@
+use std::io::Read;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use arrow_array::builder::{
+    BinaryBuilder, BooleanBuilder, Decimal256Builder, Int32Builder, Int64Builder, StringBuilder,
+    StringDictionaryBuilder, TimestampMicrosecondBuilder,
+};
+use arrow_array::cast::AsArray;
+use arrow_array::types::{Decimal256Type, Int32Type, Int64Type, TimestampMicrosecondType};
+use arrow_array::{Array, ArrayRef, RecordBatch};
+use arrow_buffer::i256;
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use bigdecimal::BigDecimal;
+use chrono::{DateTime, NaiveDateTime};
+use num_bigint::BigInt;
+use parquet::arrow::arrow_reader::{ParquetRecordBatchReader, ParquetRecordBatchReaderBuilder};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::{GzipLevel, ZstdLevel};
+use parquet::file::properties::WriterProperties;
+use serde_json::{Map, Value};
+
+use crate::bundle::fields::struct_fields;
+use crate::bundle::*;
+use crate::utils::*;
+
+/// Rows written as a single row group and read as a single batch
+const ROW_GROUP_SIZE: usize = 8_192;
+
+/// Digits of decimal columns, the integer part fits uint128 token amounts
+const DECIMAL_PRECISION: u8 = 76;
+
+/// Fractional digits of decimal columns, values with more can't be exported
+const DECIMAL_SCALE: i8 = 9;
+
+/// Rows as a parquet file with a column per row field
+pub(crate) struct ParquetEncoder {
+    output: ArrowWriter<HashingWriter<Box<dyn FinishWrite>>>,
+    schema: SchemaRef,
+    columns: Vec<&'static Column>,
+    rows: Vec<Map<String, Value>>,
+}
+
+impl ParquetEncoder {
+    /// Pages are compressed with `compression` instead of the whole file,
+    /// columns of [`BundleRow::SECRET_FIELDS`] are left out unless `secret_fields` is set
+    pub(crate) fn new<R: BundleRow>(
+        output: HashingWriter<Box<dyn FinishWrite>>,
+        compression: Compression,
+        secret_fields: bool,
+    ) -> Result<Self> {
+        let columns = columns::<R>()?
+            .iter()
+            .filter(|column| secret_fields || !R::SECRET_FIELDS.contains(&column.name))
+            .collect::<Vec<_>>();
+        let schema = schema(&columns);
+
+        let compression = match compression {
+            Compression::None => parquet::basic::Compression::UNCOMPRESSED,
+            Compression::Gzip => parquet::basic::Compression::GZIP(GzipLevel::default()),
+            Compression::Zstd => parquet::basic::Compression::ZSTD(ZstdLevel::default()),
+        };
+        let properties = WriterProperties::builder()
+            .set_compression(compression)
+            .set_max_row_group_size(ROW_GROUP_SIZE)
+            .build();
+
+        Ok(Self {
+            output: ArrowWriter::try_new(output, schema.clone(), Some(properties))?,
+            schema,
+            columns,
+            rows: Vec::with_capacity(ROW_GROUP_SIZE),
+        })
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if self.rows.is_empty() {
+            return Ok(());
+        }
+
+        let arrays = self
+            .columns
+            .iter()
+            .map(|column| {
+                column
+                    .array(&self.rows)
+                    .with_context(|| format!("Invalid {} value", column.name))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        self.output
+            .write(&RecordBatch::try_new(self.schema.clone(), arrays)?)?;
+        self.rows.clear();
+
+        Ok(())
+    }
+}
+
+impl<R: BundleRow> RowEncoder<R> for ParquetEncoder {
+    fn write(&mut self, row: &R) -> Result<()> {
+        match serde_json::to_value(row)? {
+            Value::Object(row) => self.rows.push(row),
+            _ => anyhow::bail!("{} rows are not structs", R::ENTITY),
+        }
+
+        if self.rows.len() >= ROW_GROUP_SIZE {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<HashingWriter<Box<dyn FinishWrite>>> {
+        self.flush()?;
+        Ok(self.output.into_inner()?)
+    }
+}
+
+/// Rows of a parquet file, which is copied to a temporary one first
+/// since parquet is read from the end and bundle files are streamed
+pub(crate) struct ParquetDecoder {
+    batches: ParquetRecordBatchReader,
+    columns: &'static [Column],
+    batch: Vec<ArrayRef>,
+    len: usize,
+    index: usize,
+}
+
+impl ParquetDecoder {
+    pub(crate) fn new<R: BundleRow>(mut file: Box<dyn Read>) -> Result<Self> {
+        let mut spooled = tempfile::tempfile()?;
+        std::io::copy(&mut file, &mut spooled)?;
+
+        let builder = ParquetRecordBatchReaderBuilder::try_new(spooled)?;
+        if let Some(field) = R::SECRET_FIELDS
+            .iter()
+            .find(|field| builder.schema().field_with_name(field).is_err())
+        {
+            anyhow::bail!(
+                "File has no {} column, it is left out of parquet exports, use jsonl to import them",
+                field
+            );
+        }
+        let batches = builder.with_batch_size(ROW_GROUP_SIZE).build()?;
+
+        Ok(Self {
+            batches,
+            columns: columns::<R>()?,
+            batch: Vec::new(),
+            len: 0,
+            index: 0,
+        })
+    }
+
+    /// Makes the next batch current, returns `false` at the end of the file
+    fn next_batch(&mut self) -> Result<bool> {
+        let batch = match self.batches.next() {
+            Some(batch) => batch?,
+            None => return Ok(false),
+        };
+
+        self.batch = self
+            .columns
+            .iter()
+            .map(|column| {
+                batch
+                    .column_by_name(column.name)
+                    .cloned()
+                    .with_context(|| format!("File has no {} column", column.name))
+            })
+            .collect::<Result<_>>()?;
+        self.len = batch.num_rows();
+        self.index = 0;
+
+        Ok(true)
+    }
+}
+
+impl<R: BundleRow> RowDecoder<R> for ParquetDecoder {
+    fn next_row(&mut self) -> Result<Option<R>> {
+        while self.index >= self.len {
+            if !self.next_batch()? {
+                return Ok(None);
+            }
+        }
+
+        let mut row = Map::with_capacity(self.columns.len());
+        for (column, array) in self.columns.iter().zip(&self.batch) {
+            let value = column
+                .value(array, self.index)
+                .with_context(|| format!("Invalid {} value", column.name))?;
+            row.insert(column.name.to_owned(), value);
+        }
+        self.index += 1;
+
+        Ok(Some(serde_json::from_value(Value::Object(row))?))
+    }
+
+    fn skip(&mut self, rows: usize, _offset: u64) -> Result<()> {
+        let mut remaining = rows;
+        while remaining > 0 {
+            if self.index >= self.len && !self.next_batch()? {
+                break;
+            }
+            let skipped = remaining.min(self.len - self.index);
+            self.index += skipped;
+            remaining -= skipped;
+        }
+        Ok(())
+    }
+
+    fn offset(&self) -> u64 {
+        0
+    }
+}
+
+/// Parquet column of a row field
+pub(crate) struct Column {
+    name: &'static str,
+    kind: ColumnKind,
+    nullable: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ColumnKind {
+    Text,
+    Int32,
+    Int64,
+    Bool,
+    Decimal,
+    Timestamp,
+    Enum,
+    /// JSON values, stored as text
+    Json,
+    Bytes,
+}
+
+const fn required(name: &'static str, kind: ColumnKind) -> Column {
+    Column {
+        name,
+        kind,
+        nullable: false,
+    }
+}
+
+const fn optional(name: &'static str, kind: ColumnKind) -> Column {
+    Column {
+        name,
+        kind,
+        nullable: true,
+    }
+}
+
+const ADDRESS_COLUMNS: &[Column] = &[
+    required("id", ColumnKind::Text),
+    required("service_id", ColumnKind::Text),
+    required("workchain_id", ColumnKind::Int32),
+    required("hex", ColumnKind::Text),
+    required("base64url", ColumnKind::Text),
+    required("public_key", ColumnKind::Text),
+    required("private_key", ColumnKind::Text),
+    required("account_type", ColumnKind::Enum),
+    optional("custodians", ColumnKind::Int32),
+    optional("confirmations", ColumnKind::Int32),
+    optional("custodians_public_keys", ColumnKind::Json),
+    required("balance", ColumnKind::Decimal),
+    required("created_at", ColumnKind::Timestamp),
+    required("updated_at", ColumnKind::Timestamp),
+];
+
+const TRANSACTION_COLUMNS: &[Column] = &[
+    required("id", ColumnKind::Text),
+    required("service_id", ColumnKind::Text),
+    required("message_hash", ColumnKind::Text),
+    optional("transaction_hash", ColumnKind::Text),
+    optional("transaction_lt", ColumnKind::Decimal),
+    optional("transaction_timeout", ColumnKind::Int64),
+    optional("transaction_scan_lt", ColumnKind::Int64),
+    optional("transaction_timestamp", ColumnKind::Timestamp),
+    optional("sender_workchain_id", ColumnKind::Int32),
+    optional("sender_hex", ColumnKind::Text),
+    required("account_workchain_id", ColumnKind::Int32),
+    required("account_hex", ColumnKind::Text),
+    optional("messages", ColumnKind::Json),
+    optional("messages_hash", ColumnKind::Json),
+    optional("data", ColumnKind::Json),
+    optional("original_value", ColumnKind::Decimal),
+    optional("original_outputs", ColumnKind::Json),
+    optional("value", ColumnKind::Decimal),
+    optional("fee", ColumnKind::Decimal),
+    optional("balance_change", ColumnKind::Decimal),
+    required("direction", ColumnKind::Enum),
+    required("status", ColumnKind::Enum),
+    optional("error", ColumnKind::Text),
+    required("aborted", ColumnKind::Bool),
+    required("bounce", ColumnKind::Bool),
+    required("created_at", ColumnKind::Timestamp),
+    required("updated_at", ColumnKind::Timestamp),
+];
+
+const TOKEN_OWNER_COLUMNS: &[Column] = &[
+    required("address", ColumnKind::Text),
+    required("owner_account_workchain_id", ColumnKind::Int32),
+    required("owner_account_hex", ColumnKind::Text),
+    required("root_address", ColumnKind::Text),
+    required("code_hash", ColumnKind::Bytes),
+    required("created_at", ColumnKind::Timestamp),
+];
+
+const TOKEN_TRANSACTION_COLUMNS: &[Column] = &[
+    required("id", ColumnKind::Text),
+    required("service_id", ColumnKind::Text),
+    optional("transaction_hash", ColumnKind::Text),
+    optional("transaction_timestamp", ColumnKind::Timestamp),
+    required("message_hash", ColumnKind::Text),
+    optional("owner_message_hash", ColumnKind::Text),
+    required("account_workchain_id", ColumnKind::Int32),
+    required("account_hex", ColumnKind::Text),
+    required("value", ColumnKind::Decimal),
+    required("root_address", ColumnKind::Text),
+    optional("payload", ColumnKind::Bytes),
+    optional("error", ColumnKind::Text),
+    optional("block_hash", ColumnKind::Text),
+    optional("block_time", ColumnKind::Int32),
+    required("direction", ColumnKind::Enum),
+    required("status", ColumnKind::Enum),
+    required("created_at", ColumnKind::Timestamp),
+    required("updated_at", ColumnKind::Timestamp),
+];
+
+/// Columns of `R`, checked against its fields so that the tables follow the row structs
+fn columns<R: BundleRow>() -> Result<&'static [Column]> {
+    let columns = match R::ENTITY {
+        Entity::Addresses => ADDRESS_COLUMNS,
+        Entity::Transactions => TRANSACTION_COLUMNS,
+        Entity::TokenOwners => TOKEN_OWNER_COLUMNS,
+        Entity::TokenTransactions => TOKEN_TRANSACTION_COLUMNS,
+    };
+
+    let fields = struct_fields::<R>()?;
+    if !columns
+        .iter()
+        .map(|column| column.name)
+        .eq(fields.iter().copied())
+    {
+        anyhow::bail!(
+            "Parquet columns of {} don't match the row fields",
+            R::ENTITY
+        );
+    }
+
+    Ok(columns)
+}
+
+fn schema(columns: &[&Column]) -> SchemaRef {
+    Arc::new(Schema::new(
+        columns
+            .iter()
+            .map(|column| Field::new(column.name, column.kind.data_type(), column.nullable))
+            .collect::<Vec<_>>(),
+    ))
+}
+
+impl ColumnKind {
+    fn data_type(self) -> DataType {
+        match self {
+            ColumnKind::Text | ColumnKind::Json => DataType::Utf8,
+            ColumnKind::Int32 => DataType::Int32,
+            ColumnKind::Int64 => DataType::Int64,
+            ColumnKind::Bool => DataType::Boolean,
+            ColumnKind::Decimal => DataType::Decimal256(DECIMAL_PRECISION, DECIMAL_SCALE),
+            ColumnKind::Timestamp => DataType::Timestamp(TimeUnit::Microsecond, None),
+            ColumnKind::Enum => {
+                DataType::Dictionary(Box::new(DataType::Int32), Box::new(DataType::Utf8))
+            }
+            ColumnKind::Bytes => DataType::Binary,
+        }
+    }
+}
+
+impl Column {
+    /// Values of the column in `rows`, as serialized by serde
+    fn array(&self, rows: &[Map<String, Value>]) -> Result<ArrayRef> {
+        let values = rows
+            .iter()
+            .map(|row| row.get(self.name).filter(|value| !value.is_null()));
+
+        Ok(match self.kind {
+            ColumnKind::Text => {
+                let mut builder = StringBuilder::new();
+                for value in values {
+                    builder.append_option(value.map(text).transpose()?);
+                }
+                Arc::new(builder.finish())
+            }
+            ColumnKind::Json => {
+                let mut builder = StringBuilder::new();
+                for value in values {
+                    builder.append_option(value.map(Value::to_string));
+                }
+                Arc::new(builder.finish())
+            }
+            ColumnKind::Int32 => {
+                let mut builder = Int32Builder::new();
+                for value in values {
+                    builder.append_option(value.map(integer::<i32>).transpose()?);
+                }
+                Arc::new(builder.finish())
+            }
+            ColumnKind::Int64 => {
+                let mut builder = Int64Builder::new();
+                for value in values {
+                    builder.append_option(value.map(integer::<i64>).transpose()?);
+                }
+                Arc::new(builder.finish())
+            }
+            ColumnKind::Bool => {
+                let mut builder = BooleanBuilder::new();
+                for value in values {
+                    builder.append_option(value.map(boolean).transpose()?);
+                }
+                Arc::new(builder.finish())
+            }
+            ColumnKind::Decimal => {
+                let mut builder = Decimal256Builder::new()
+                    .with_precision_and_scale(DECIMAL_PRECISION, DECIMAL_SCALE)?;
+                for value in values {
+                    builder.append_option(value.map(decimal).transpose()?);
+                }
+                Arc::new(builder.finish())
+            }
+            ColumnKind::Timestamp => {
+                let mut builder = TimestampMicrosecondBuilder::new();
+                for value in values {
+                    builder.append_option(value.map(timestamp).transpose()?);
+                }
+                Arc::new(builder.finish())
+            }
+            ColumnKind::Enum => {
+                let mut builder = StringDictionaryBuilder::<Int32Type>::new();
+                for value in values {
+                    match value {
+                        Some(value) => {
+                            builder.append(text(value)?)?;
+                        }
+                        None => builder.append_null(),
+                    }
+                }
+                Arc::new(builder.finish())
+            }
+            ColumnKind::Bytes => {
+                let mut builder = BinaryBuilder::new();
+                for value in values {
+                    builder.append_option(value.map(bytes).transpose()?);
+                }
+                Arc::new(builder.finish())
+            }
+        })
+    }
+
+    /// Value at `index` of `array`, as deserialized by serde
+    fn value(&self, array: &ArrayRef, index: usize) -> Result<Value> {
+        if array.is_null(index) {
+            return Ok(Value::Null);
+        }
+
+        let unexpected = || {
+            anyhow::anyhow!(
+                "expected {:?}, found {}",
+                self.kind.data_type(),
+                array.data_type()
+            )
+        };
+
+        Ok(match self.kind {
+            ColumnKind::Text => {
+                let array = array.as_string_opt::<i32>().ok_or_else(unexpected)?;
+                Value::from(array.value(index))
+            }
+            ColumnKind::Json => {
+                let array = array.as_string_opt::<i32>().ok_or_else(unexpected)?;
+                serde_json::from_str(array.value(index))?
+            }
+            ColumnKind::Int32 => {
+                let array = array
+                    .as_primitive_opt::<Int32Type>()
+                    .ok_or_else(unexpected)?;
+                Value::from(array.value(index))
+            }
+            ColumnKind::Int64 => {
+                let array = array
+                    .as_primitive_opt::<Int64Type>()
+                    .ok_or_else(unexpected)?;
+                Value::from(array.value(index))
+            }
+            ColumnKind::Bool => {
+                let array = array.as_boolean_opt().ok_or_else(unexpected)?;
+                Value::from(array.value(index))
+            }
+            ColumnKind::Decimal => {
+                let array = array
+                    .as_primitive_opt::<Decimal256Type>()
+                    .ok_or_else(unexpected)?;
+                Value::from(decimal_string(array.value(index), array.scale())?)
+            }
+            ColumnKind::Timestamp => {
+                let array = array
+                    .as_primitive_opt::<TimestampMicrosecondType>()
+                    .ok_or_else(unexpected)?;
+                let timestamp = DateTime::from_timestamp_micros(array.value(index))
+                    .context("timestamp is out of range")?;
+                serde_json::to_value(timestamp.naive_utc())?
+            }
+            ColumnKind::Enum => {
+                let array = array
+                    .as_dictionary_opt::<Int32Type>()
+                    .ok_or_else(unexpected)?;
+                let values = array
+                    .values()
+                    .as_string_opt::<i32>()
+                    .ok_or_else(unexpected)?;
+                Value::from(values.value(array.keys().value(index) as usize))
+            }
+            ColumnKind::Bytes => {
+                let array = array.as_binary_opt::<i32>().ok_or_else(unexpected)?;
+                serde_json::to_value(array.value(index))?
+            }
+        })
+    }
+}
+
+fn text(value: &Value) -> Result<&str> {
+    value
+        .as_str()
+        .with_context(|| format!("expected a string, found {}", value))
+}
+
+fn integer<T: TryFrom<i64>>(value: &Value) -> Result<T> {
+    value
+        .as_i64()
+        .and_then(|value| T::try_from(value).ok())
+        .with_context(|| format!("expected an integer, found {}", value))
+}
+
+fn boolean(value: &Value) -> Result<bool> {
+    value
+        .as_bool()
+        .with_context(|| format!("expected a boolean, found {}", value))
+}
+
+fn bytes(value: &Value) -> Result<Vec<u8>> {
+    Ok(serde_json::from_value(value.clone())?)
+}
+
+/// Microseconds since the epoch
+fn timestamp(value: &Value) -> Result<i64> {
+    let timestamp: NaiveDateTime = serde_json::from_value(value.clone())?;
+    Ok(timestamp.and_utc().timestamp_micros())
+}
+
+/// Digits of a decimal with [`DECIMAL_SCALE`] fractional ones, failing instead of rounding
+fn decimal(value: &Value) -> Result<i256> {
+    let value = BigDecimal::from_str(text(value)?)?;
+
+    let scaled = value.with_scale(DECIMAL_SCALE.into());
+    if scaled != value {
+        anyhow::bail!(
+            "{} has more than {} fractional digits",
+            value,
+            DECIMAL_SCALE
+        );
+    }
+
+    let (digits, _) = scaled.as_bigint_and_exponent();
+    let digits = digits.to_string();
+    Some(&digits)
+        .filter(|digits| digits.trim_start_matches('-').len() <= DECIMAL_PRECISION.into())
+        .and_then(|digits| i256::from_string(digits))
+        .with_context(|| {
+            format!(
+                "{} doesn't fit decimal({}, {})",
+                value, DECIMAL_PRECISION, DECIMAL_SCALE
+            )
+        })
+}
+
+/// Decimal string without trailing fractional zeros, which parquet adds up to the column scale
+fn decimal_string(digits: i256, mut scale: i8) -> Result<String> {
+    let mut digits = digits.to_string();
+    while scale > 0 && digits.ends_with('0') {
+        digits.pop();
+        scale -= 1;
+    }
+
+    // Zero loses all of its digits
+    if digits.is_empty() {
+        return Ok("0".to_owned());
+    }
+    Ok(BigDecimal::new(BigInt::from_str(&digits)?, scale.into()).to_string())
+}
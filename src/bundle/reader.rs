@@ -0,0 +1,210 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use zeroize::Zeroizing;
+
+use crate::bundle::parquet_rows::ParquetDecoder;
+use crate::bundle::*;
+
+/// Reads the manifest and entity files of a bundle directory or archive
+pub struct BundleReader {
+    source: BundleSource,
+    manifest: Manifest,
+    transport_passphrase: Option<Zeroizing<String>>,
+}
+
+impl BundleReader {
+    /// Opens the bundle at `path`, encrypted files are read with `transport_passphrase`
+    pub fn open(path: PathBuf, transport_passphrase: Option<&str>) -> Result<Self> {
+        let source = BundleSource::new(path)?;
+        let manifest = Manifest::load(&source)?;
+        if !manifest.format.is_importable() {
+            anyhow::bail!(
+                "{} is a {} bundle, which can't be read back",
+                source.path().display(),
+                manifest.format
+            );
+        }
+
+        Ok(Self {
+            source,
+            manifest,
+            transport_passphrase: transport_passphrase.map(|p| Zeroizing::new(p.to_owned())),
+        })
+    }
+
+    pub fn source(&self) -> &BundleSource {
+        &self.source
+    }
+
+    pub fn manifest(&self) -> &Manifest {
+        &self.manifest
+    }
+
+    /// Checks files of `entities` against the manifest checksums, see [`Manifest::verify`]
+    pub fn verify(&self, entities: &[Entity]) -> Result<()> {
+        self.manifest
+            .verify(&self.source, entities, self.transport_passphrase())
+    }
+
+    /// Rows of `R` in the order they were exported
+    pub fn entity<R: BundleRow>(&self) -> Result<EntityReader<R>> {
+        let name = self.manifest.file(R::ENTITY);
+        let encrypted = self
+            .manifest
+            .files
+            .get(&name)
+            .is_some_and(|file| file.encryption.is_some());
+        if encrypted && self.manifest.format == BundleFormat::Parquet {
+            anyhow::bail!("{} is encrypted, which isn't supported for parquet", name);
+        }
+
+        let open = || {
+            self.manifest
+                .open(&self.source, &name, self.transport_passphrase())
+        };
+
+        let rows: Box<dyn RowDecoder<R>> = match self.manifest.format {
+            BundleFormat::Jsonl => match self.manifest.open_plain(&self.source, &name)? {
+                Some(file) => Box::new(JsonlDecoder::new(file)),
+                None => Box::new(JsonlDecoder::new(open()?)),
+            },
+            BundleFormat::Parquet => Box::new(
+                ParquetDecoder::new::<R>(open()?)
+                    .with_context(|| format!("Failed to open {}", name))?,
+            ),
+            BundleFormat::Csv => anyhow::bail!("{} can't be read back", name),
+        };
+
+        Ok(EntityReader {
+            name,
+            rows,
+            line: 0,
+        })
+    }
+
+    fn transport_passphrase(&self) -> Option<&str> {
+        self.transport_passphrase.as_ref().map(|p| p.as_str())
+    }
+}
+
+/// Rows of a single entity, errors name the file and line
+pub struct EntityReader<R> {
+    name: String,
+    rows: Box<dyn RowDecoder<R>>,
+    line: usize,
+}
+
+impl<R: BundleRow> EntityReader<R> {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Number of the last read line, rows of formats without lines are numbered the same way
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// Byte offset right after the last read line, zero for formats without lines
+    pub fn offset(&self) -> u64 {
+        self.rows.offset()
+    }
+
+    /// Skips rows up to `line` ending at `offset`, as recorded by a previous reader
+    pub fn skip_to(&mut self, line: usize, offset: u64) -> Result<()> {
+        self.rows.skip(line.saturating_sub(self.line), offset)?;
+        self.line = line.max(self.line);
+        Ok(())
+    }
+}
+
+impl<R: BundleRow> Iterator for EntityReader<R> {
+    type Item = crate::Result<R>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let row = self.rows.next_row().transpose()?;
+        self.line += 1;
+        Some(row.map_err(|e| crate::Error::CorruptLine {
+            file: self.name.clone(),
+            line: self.line,
+            source: e.into(),
+        }))
+    }
+}
+
+/// Decoding of rows in one of the bundle formats
+pub(crate) trait RowDecoder<R> {
+    fn next_row(&mut self) -> Result<Option<R>>;
+
+    /// Skips `rows` rows, which end at `offset` in formats with lines
+    fn skip(&mut self, rows: usize, offset: u64) -> Result<()>;
+
+    fn offset(&self) -> u64;
+}
+
+struct JsonlDecoder<F> {
+    reader: BufReader<F>,
+    offset: u64,
+    buffer: String,
+}
+
+impl<F: Read> JsonlDecoder<F> {
+    fn new(file: F) -> Self {
+        Self {
+            reader: BufReader::new(file),
+            offset: 0,
+            buffer: String::new(),
+        }
+    }
+}
+
+impl<R: BundleRow, F: Forward> RowDecoder<R> for JsonlDecoder<F> {
+    fn next_row(&mut self) -> Result<Option<R>> {
+        self.buffer.clear();
+        let read = self.reader.read_line(&mut self.buffer)?;
+        if read == 0 {
+            return Ok(None);
+        }
+        self.offset += read as u64;
+
+        Ok(Some(serde_json::from_str(&self.buffer)?))
+    }
+
+    fn skip(&mut self, _rows: usize, offset: u64) -> Result<()> {
+        if offset > self.offset {
+            F::forward(&mut self.reader, offset - self.offset)?;
+            self.offset = offset;
+        }
+        Ok(())
+    }
+
+    fn offset(&self) -> u64 {
+        self.offset
+    }
+}
+
+/// Moves a reader forward by a number of bytes
+trait Forward: Read + Sized {
+    fn forward(reader: &mut BufReader<Self>, bytes: u64) -> io::Result<()>;
+}
+
+/// Plain files seek
+impl Forward for File {
+    fn forward(reader: &mut BufReader<Self>, bytes: u64) -> io::Result<()> {
+        reader.seek(SeekFrom::Current(bytes as i64))?;
+        Ok(())
+    }
+}
+
+/// Compressed, encrypted and archived files can't seek, so the bytes are read and dropped
+impl Forward for Box<dyn Read> {
+    fn forward(reader: &mut BufReader<Self>, bytes: u64) -> io::Result<()> {
+        let skipped = io::copy(&mut reader.take(bytes), &mut io::sink())?;
+        if skipped < bytes {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,173 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use zeroize::Zeroizing;
+
+use crate::bundle::csv_rows::CsvEncoder;
+use crate::bundle::parquet_rows::ParquetEncoder;
+use crate::bundle::*;
+use crate::utils::*;
+
+/// Writes entity files into a bundle directory and the manifest listing them
+pub struct BundleWriter {
+    path: PathBuf,
+    compression: Compression,
+    transport_passphrase: Option<Zeroizing<String>>,
+    secret_fields: bool,
+    manifest: Manifest,
+}
+
+impl BundleWriter {
+    /// Writer of `format` files into the existing directory `path`,
+    /// `manifest` gets the format and the written files
+    pub fn new(
+        path: PathBuf,
+        mut manifest: Manifest,
+        format: BundleFormat,
+        compression: Compression,
+    ) -> Self {
+        manifest.format = format;
+        Self {
+            path,
+            compression,
+            transport_passphrase: None,
+            secret_fields: false,
+            manifest,
+        }
+    }
+
+    /// Encrypts files of rows with [`BundleRow::SECRET_FIELDS`] with `transport_passphrase`
+    pub fn with_transport_passphrase(mut self, transport_passphrase: Option<&str>) -> Self {
+        self.transport_passphrase = transport_passphrase.map(|p| Zeroizing::new(p.to_owned()));
+        self
+    }
+
+    /// Keeps [`BundleRow::SECRET_FIELDS`] in formats which drop them, jsonl always has them
+    pub fn with_secret_fields(mut self, secret_fields: bool) -> Self {
+        self.secret_fields = secret_fields;
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Creates the file of `R`, it is listed in the manifest once finished
+    pub fn entity<R: BundleRow>(&mut self) -> Result<EntityWriter<'_, R>> {
+        let name = self.manifest.file(R::ENTITY);
+        // Parquet files have secret fields only on request
+        let secret = !R::SECRET_FIELDS.is_empty()
+            && (self.secret_fields || self.manifest.format != BundleFormat::Parquet);
+        let passphrase = match secret {
+            true => self.transport_passphrase.as_ref().map(|p| p.as_str()),
+            false => None,
+        };
+        // Parquet is read from a temporary copy, which would hold the decrypted secrets
+        if passphrase.is_some() && self.manifest.format == BundleFormat::Parquet {
+            anyhow::bail!(
+                "{} can't be encrypted, use jsonl with a transport passphrase",
+                name
+            );
+        }
+        let encryption = passphrase.map(|_| TransportEncryption::generate());
+
+        // Parquet compresses pages itself, so its files stay readable by other tools
+        let compression = match self.manifest.format {
+            BundleFormat::Parquet => Compression::None,
+            _ => self.compression,
+        };
+
+        let summary = ManifestFile {
+            rows: 0,
+            sha256: String::new(),
+            encryption,
+        };
+        let file = File::create(self.path.join(summary.stored_name(&name, compression)))?;
+
+        // Rows are compressed first, encrypted data doesn't compress
+        let mut output: Box<dyn FinishWrite> = Box::new(file);
+        if let (Some(encryption), Some(passphrase)) = (&summary.encryption, passphrase) {
+            output = Box::new(encryption.encryptor(passphrase, output)?);
+        }
+        let output = HashingWriter::new(compression.encoder(output)?);
+
+        let rows: Box<dyn RowEncoder<R>> = match self.manifest.format {
+            BundleFormat::Jsonl => Box::new(JsonlEncoder {
+                output: BufWriter::new(output),
+            }),
+            BundleFormat::Csv => Box::new(CsvEncoder::new::<R>(output, self.secret_fields)?),
+            BundleFormat::Parquet => Box::new(ParquetEncoder::new::<R>(
+                output,
+                self.compression,
+                self.secret_fields,
+            )?),
+        };
+
+        Ok(EntityWriter {
+            bundle: self,
+            rows,
+            summary,
+        })
+    }
+
+    /// Stores the manifest
+    pub fn finish(self) -> Result<Manifest> {
+        self.manifest.store(&self.path)?;
+        Ok(self.manifest)
+    }
+}
+
+/// Output of a single entity, which tracks the row count and checksum for the manifest
+pub struct EntityWriter<'a, R> {
+    bundle: &'a mut BundleWriter,
+    rows: Box<dyn RowEncoder<R>>,
+    summary: ManifestFile,
+}
+
+impl<R: BundleRow> EntityWriter<'_, R> {
+    pub fn write(&mut self, row: &R) -> Result<()> {
+        self.rows.write(row)?;
+        self.summary.rows += 1;
+        Ok(())
+    }
+
+    /// Flushes the file to the disk and lists it in the manifest, returns the number of rows
+    pub fn finish(mut self) -> Result<u64> {
+        let (output, sha256) = self.rows.finish()?.finish();
+        output.finish_write()?;
+
+        self.summary.sha256 = sha256;
+
+        let rows = self.summary.rows;
+        let name = self.bundle.manifest.file(R::ENTITY);
+        self.bundle.manifest.files.insert(name, self.summary);
+
+        Ok(rows)
+    }
+}
+
+/// Encoding of rows in one of the bundle formats
+pub(crate) trait RowEncoder<R> {
+    fn write(&mut self, row: &R) -> Result<()>;
+
+    /// Writes out buffered rows, returns the output they were written to
+    fn finish(self: Box<Self>) -> Result<HashingWriter<Box<dyn FinishWrite>>>;
+}
+
+struct JsonlEncoder {
+    output: BufWriter<HashingWriter<Box<dyn FinishWrite>>>,
+}
+
+impl<R: BundleRow> RowEncoder<R> for JsonlEncoder {
+    fn write(&mut self, row: &R) -> Result<()> {
+        serde_json::to_writer(&mut self.output, row)?;
+        self.output.write_all(b"\n")?;
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<HashingWriter<Box<dyn FinishWrite>>> {
+        Ok(self.output.into_inner().map_err(|e| e.into_error())?)
+    }
+}
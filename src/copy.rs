@@ -0,0 +1,270 @@
+use std::fmt;
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use bigdecimal::BigDecimal;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use zeroize::Zeroizing;
+
+use crate::bundle::BundleRow;
+use crate::import::{Importer, RowSource, DEFAULT_BATCH_SIZE};
+use crate::models::*;
+use crate::repository::RepositoryRow;
+use crate::sqlx_client::*;
+use crate::utils::*;
+
+#[derive(Debug, Clone)]
+pub struct CopyOptions {
+    /// Restricts copied rows the same way as for export
+    pub filter: ExportFilter,
+    /// Copied entities
+    pub entities: Vec<Entity>,
+    /// Rows sent to the target database in a single `COPY`
+    pub batch_size: usize,
+    /// Copy everything in one transaction, rolled back on any error
+    pub atomic: bool,
+    /// Handling of addresses and transactions that already exist in the target
+    pub on_conflict: OnConflict,
+    /// Expected key check value of the source key, see [`key_check`]
+    pub source_key_check: Option<String>,
+    /// Expected key check value of the target key
+    pub target_key_check: Option<String>,
+}
+
+impl Default for CopyOptions {
+    fn default() -> Self {
+        Self {
+            filter: ExportFilter::default(),
+            entities: Entity::ALL.to_vec(),
+            batch_size: DEFAULT_BATCH_SIZE,
+            atomic: false,
+            on_conflict: OnConflict::Fail,
+            source_key_check: None,
+            target_key_check: None,
+        }
+    }
+}
+
+/// Database and service rows are copied from or into, with the key its private keys are encrypted with
+pub struct CopyEndpoint {
+    pub database_url: String,
+    pub service_id: ServiceId,
+    pub key: Zeroizing<[u8; 32]>,
+}
+
+/// Streams rows of `source` into `target` without writing them anywhere else,
+/// private keys are re-encrypted with the target key if it differs
+pub async fn run_copy(
+    source: CopyEndpoint,
+    target: CopyEndpoint,
+    options: CopyOptions,
+) -> Result<CopyReport> {
+    let source_client = SqlxClient::new(
+        get_pg_pool_for(&source.database_url)
+            .await
+            .context("Failed to connect to the source database")?,
+    );
+    let target_client = SqlxClient::new(
+        get_pg_pool_for(&target.database_url)
+            .await
+            .context("Failed to connect to the target database")?,
+    );
+
+    // Row ids are kept, so every copied row would conflict with its own source row
+    if source_client.database_identity().await? == target_client.database_identity().await? {
+        anyhow::bail!(
+            "Source and target are the same database, rows can only be copied into another one"
+        );
+    }
+
+    // Fail before copying anything rather than on the first address
+    check_key(
+        &source_client,
+        source.service_id,
+        &source.key,
+        options.source_key_check.as_deref(),
+    )
+    .await
+    .context("Source key check failed")?;
+    check_key(
+        &target_client,
+        target.service_id,
+        &target.key,
+        options.target_key_check.as_deref(),
+    )
+    .await
+    .context("Target key check failed")?;
+
+    let mut importer = Importer::new(
+        &target_client,
+        options.batch_size,
+        options.atomic,
+        options.on_conflict,
+    );
+
+    let service_id = source.service_id;
+    let filter = &options.filter;
+    let target_id = target.service_id;
+
+    let result = async {
+        let mut report = CopyReport::default();
+        for &entity in &options.entities {
+            let copied = match entity {
+                Entity::Addresses => {
+                    copy_entity(
+                        &mut importer,
+                        source_client.stream_all_addresses(service_id, filter),
+                        |address: &mut AddressDb| {
+                            reencrypt(address, &source.key, &target.key)?;
+                            address.service_id = target_id;
+                            // Balances are synced by the target service
+                            address.balance = BigDecimal::from(0);
+                            Ok(())
+                        },
+                    )
+                    .await?
+                }
+                Entity::Transactions => {
+                    copy_entity(
+                        &mut importer,
+                        source_client.stream_all_transactions(service_id, filter),
+                        |transaction: &mut TransactionDb| {
+                            transaction.service_id = target_id;
+                            Ok(())
+                        },
+                    )
+                    .await?
+                }
+                Entity::TokenOwners => {
+                    copy_entity(
+                        &mut importer,
+                        source_client.stream_all_token_owners(service_id, filter),
+                        |_: &mut TokenOwnerDb| Ok(()),
+                    )
+                    .await?
+                }
+                Entity::TokenTransactions => {
+                    copy_entity(
+                        &mut importer,
+                        source_client.stream_all_token_transactions(service_id, filter),
+                        |token_transaction: &mut TokenTransactionDb| {
+                            token_transaction.service_id = target_id;
+                            Ok(())
+                        },
+                    )
+                    .await?
+                }
+            };
+            report.entities.push(copied);
+        }
+        Ok::<_, anyhow::Error>(report)
+    }
+    .await;
+
+    match result {
+        Ok(report) => {
+            importer.finish().await?;
+            Ok(report)
+        }
+        Err(e) if options.atomic => Err(e.context("Copy rolled back")),
+        Err(e) => Err(e),
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CopyReport {
+    pub entities: Vec<CopiedEntity>,
+}
+
+impl fmt::Display for CopyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.entities.iter().try_for_each(|entity| entity.fmt(f))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CopiedEntity {
+    pub entity: Entity,
+    /// Rows read from the source
+    pub read: u64,
+    /// Rows inserted or updated in the target
+    pub written: u64,
+}
+
+impl fmt::Display for CopiedEntity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{}: {} read, {} written",
+            self.entity, self.read, self.written
+        )
+    }
+}
+
+/// Replaces the private key encrypted with `source_key` by one encrypted with `target_key`
+fn reencrypt(address: &mut AddressDb, source_key: &[u8; 32], target_key: &[u8; 32]) -> Result<()> {
+    if source_key == target_key {
+        return Ok(());
+    }
+
+    let private_key =
+        decrypt(&address.private_key, source_key, &address.id).with_context(|| {
+            format!(
+                "Failed to decrypt address {} with the source key",
+                address.id
+            )
+        })?;
+    let encoded = Zeroizing::new(base64::encode(&*private_key));
+    address.private_key = encrypt(&encoded, target_key, &address.id)?;
+
+    Ok(())
+}
+
+/// Writes rows streamed from the source, prepared for the target by `prepare`
+async fn copy_entity<R, F>(
+    importer: &mut Importer<'_, SqlxClient>,
+    rows: BoxStream<'_, crate::Result<R>>,
+    prepare: F,
+) -> Result<CopiedEntity>
+where
+    R: RepositoryRow + BundleRow,
+    F: FnMut(&mut R) -> crate::Result<()>,
+{
+    let mut source = SourceRows {
+        name: format!("source {}", R::ENTITY),
+        rows,
+        line: 0,
+    };
+    let written = importer.import(&mut source, prepare).await?;
+
+    Ok(CopiedEntity {
+        entity: R::ENTITY,
+        read: source.line as u64,
+        written,
+    })
+}
+
+/// Rows of one entity streamed from the source database, numbered from 1 in errors
+struct SourceRows<'s, R> {
+    name: String,
+    rows: BoxStream<'s, crate::Result<R>>,
+    line: usize,
+}
+
+#[async_trait(?Send)]
+impl<R> RowSource<R> for SourceRows<'_, R> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn line(&self) -> usize {
+        self.line
+    }
+
+    async fn next_row(&mut self) -> Option<crate::Result<R>> {
+        let row = self.rows.next().await?;
+        self.line += 1;
+        Some(row)
+    }
+}
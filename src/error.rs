@@ -0,0 +1,105 @@
+use std::error::Error as StdError;
+
+use uuid::Uuid;
+
+use crate::models::*;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Errors of export, import and the database client, which embedding services may handle separately
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The secret doesn't match the key check value or the addresses of the service
+    #[error("Wrong secret for service {service_id}: {reason}")]
+    WrongKey {
+        service_id: ServiceId,
+        reason: String,
+    },
+
+    /// Row with the same primary or unique key already exists, `key` names its comma separated columns
+    /// and `id` their values, `file` and `line` are set for imported rows
+    #[error("{}{entity} row ({key})=({id}) already exists", location(.file, .line))]
+    DuplicateRow {
+        entity: Entity,
+        key: String,
+        id: String,
+        file: Option<String>,
+        line: Option<usize>,
+    },
+
+    /// Bundle line which can't be read or decoded
+    #[error("{file}: line {line} is corrupt")]
+    CorruptLine {
+        file: String,
+        line: usize,
+        #[source]
+        source: Box<dyn StdError + Send + Sync>,
+    },
+
+    /// Row which couldn't be prepared or written, the cause is the typed `source`
+    #[error("{file}: line {line}")]
+    Row {
+        file: String,
+        line: usize,
+        #[source]
+        source: Box<Error>,
+    },
+
+    /// Batch of rows the database refused without telling which one
+    #[error("{file}: lines {first_line}-{last_line}")]
+    Batch {
+        file: String,
+        first_line: usize,
+        last_line: usize,
+        #[source]
+        source: Box<Error>,
+    },
+
+    /// Connecting to the database failed or the connection was lost
+    #[error("Database is unreachable")]
+    DatabaseUnreachable(#[source] sqlx::Error),
+
+    #[error(transparent)]
+    Database(sqlx::Error),
+
+    #[error("Private key of address {id} is not base64")]
+    InvalidPrivateKey {
+        id: Uuid,
+        #[source]
+        source: base64::DecodeError,
+    },
+
+    #[error("Failed to encrypt the private key of address {id}")]
+    Encryption { id: Uuid },
+
+    /// Private key was encrypted with another key or is damaged
+    #[error("Failed to decrypt the private key of address {id}")]
+    Decryption { id: Uuid },
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+impl From<sqlx::Error> for Error {
+    fn from(error: sqlx::Error) -> Self {
+        match error {
+            sqlx::Error::Io(_)
+            | sqlx::Error::Tls(_)
+            | sqlx::Error::PoolTimedOut
+            | sqlx::Error::PoolClosed
+            | sqlx::Error::WorkerCrashed => Error::DatabaseUnreachable(error),
+            error => Error::Database(error),
+        }
+    }
+}
+
+fn location(file: &Option<String>, line: &Option<usize>) -> String {
+    match (file, line) {
+        (Some(file), Some(line)) => format!("{}: line {}: ", file, line),
+        (Some(file), None) => format!("{}: ", file),
+        _ => String::new(),
+    }
+}
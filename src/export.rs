@@ -1,113 +1,350 @@
 use std::fs::File;
-use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
-use anyhow::Result;
+use anyhow::Context;
 use bigdecimal::BigDecimal;
+use futures::stream::BoxStream;
+use futures::TryStreamExt;
+use uuid::Uuid;
+use zeroize::Zeroizing;
 
+use crate::bundle::*;
+use crate::error::*;
 use crate::models::*;
-use crate::sqlx_client::*;
+use crate::repository::*;
 use crate::utils::*;
 
-pub async fn run_export(service_id: ServiceId, path: PathBuf, key: [u8; 32]) -> Result<()> {
-    let pool = get_pg_pool().await?;
-    let sqlx_client = SqlxClient::new(pool);
+#[derive(Debug, Clone)]
+pub struct ExportOptions {
+    /// Restricts exported rows, e.g. to an `updated_at` range for incremental exports
+    pub filter: ExportFilter,
+    /// Encrypts the addresses file, so private keys are never written in plaintext
+    pub transport_passphrase: Option<Zeroizing<String>>,
+    /// Key derivation `key` was produced with, recorded in the manifest
+    pub kdf: KdfParams,
+    /// Expected key check value of `key`, see [`key_check`]
+    pub key_check: Option<String>,
+    /// Replace an existing bundle at the export path
+    pub force: bool,
+    /// Encoding of the bundle files, only jsonl bundles can be imported
+    pub format: BundleFormat,
+    /// Keep private keys in formats which exclude them by default
+    pub include_private_keys: bool,
+    /// Compression of every bundle file, the manifest is always plain
+    pub compression: Compression,
+    /// Pack the bundle into a single tar archive, compressed as a whole instead of separate files
+    pub archive: bool,
+    /// Exported entities, the manifest lists only their files
+    pub entities: Vec<Entity>,
+}
 
-    export_transactions(service_id, &sqlx_client, path.clone()).await?;
-    export_token_owners(service_id, &sqlx_client, path.clone()).await?;
-    export_token_transactions(service_id, &sqlx_client, path.clone()).await?;
-    export_addresses(service_id, &sqlx_client, path.clone(), key).await?;
+impl Default for ExportOptions {
+    fn default() -> Self {
+        Self {
+            filter: ExportFilter::default(),
+            transport_passphrase: None,
+            kdf: KdfParams::default(),
+            key_check: None,
+            force: false,
+            format: BundleFormat::Jsonl,
+            include_private_keys: false,
+            compression: Compression::None,
+            archive: false,
+            entities: Entity::ALL.to_vec(),
+        }
+    }
+}
 
-    Ok(())
+pub async fn run_export<S: Repository>(
+    repository: &S,
+    service_id: ServiceId,
+    path: PathBuf,
+    key: Zeroizing<[u8; 32]>,
+    options: ExportOptions,
+) -> Result<()> {
+    // Fail before writing anything rather than on the first address
+    check_key(repository, service_id, &key, options.key_check.as_deref()).await?;
+
+    // Files are written next to the bundle and moved into place once complete
+    if options.archive {
+        let target = archive_path(&path, options.compression);
+        let staging = StagingDir::create(&target, options.force)?;
+        write_bundle(
+            service_id,
+            repository,
+            staging.path(),
+            &key,
+            Compression::None,
+            &options,
+        )
+        .await?;
+        Ok(staging.commit_archive(options.compression)?)
+    } else {
+        let staging = StagingDir::create(&path, options.force)?;
+        write_bundle(
+            service_id,
+            repository,
+            staging.path(),
+            &key,
+            options.compression,
+            &options,
+        )
+        .await?;
+        Ok(staging.commit()?)
+    }
 }
 
-async fn export_transactions(
+async fn write_bundle<S: Repository>(
     service_id: ServiceId,
-    sqlx_client: &SqlxClient,
-    mut path: PathBuf,
+    repository: &S,
+    path: &Path,
+    key: &[u8; 32],
+    compression: Compression,
+    options: &ExportOptions,
 ) -> Result<()> {
-    let transactions = sqlx_client.get_all_transactions(service_id).await?;
+    let filter = &options.filter;
 
-    path.push("transactions.jsonl");
+    // The database clock, rows are stamped with it rather than with the clock of this host
+    let exported_at = repository.now().await?;
+    let manifest = Manifest::new(
+        service_id,
+        exported_at,
+        filter,
+        options.kdf,
+        Some(key_check(key)),
+    );
+    let mut writer = BundleWriter::new(path.to_path_buf(), manifest, options.format, compression)
+        .with_transport_passphrase(options.transport_passphrase.as_ref().map(|p| p.as_str()))
+        .with_secret_fields(options.include_private_keys);
 
-    let mut output = File::create(path)?;
-    for transaction in transactions.iter() {
-        let transaction = serde_json::to_string(transaction)? + "\n";
-        output.write_all(transaction.as_bytes())?;
+    for entity in &options.entities {
+        match entity {
+            Entity::Transactions => {
+                export_entity(
+                    &mut writer,
+                    repository.stream_all_transactions(service_id, filter),
+                    |_: &mut TransactionDb| Ok(()),
+                )
+                .await?
+            }
+            Entity::TokenOwners => {
+                export_entity(
+                    &mut writer,
+                    repository.stream_all_token_owners(service_id, filter),
+                    |_: &mut TokenOwnerDb| Ok(()),
+                )
+                .await?
+            }
+            Entity::TokenTransactions => {
+                export_entity(
+                    &mut writer,
+                    repository.stream_all_token_transactions(service_id, filter),
+                    |_: &mut TokenTransactionDb| Ok(()),
+                )
+                .await?
+            }
+            Entity::Addresses => {
+                export_entity(
+                    &mut writer,
+                    repository.stream_all_addresses(service_id, filter),
+                    |address: &mut AddressDb| {
+                        let private_key = decrypt(&address.private_key, key, &address.id)?;
+                        address.private_key = base64::encode(&*private_key);
+                        address.balance = BigDecimal::from(0);
+                        Ok(())
+                    },
+                )
+                .await?
+            }
+        }
     }
 
-    output.flush()?;
+    writer.finish()?;
 
     Ok(())
 }
 
-async fn export_token_transactions(
-    service_id: ServiceId,
-    sqlx_client: &SqlxClient,
-    mut path: PathBuf,
-) -> Result<()> {
-    let token_transactions = sqlx_client.get_all_token_transactions(service_id).await?;
+/// Writes every row of `rows` into the bundle file of its entity
+async fn export_entity<R, F>(
+    writer: &mut BundleWriter,
+    mut rows: BoxStream<'_, Result<R>>,
+    mut prepare: F,
+) -> Result<()>
+where
+    R: BundleRow,
+    F: FnMut(&mut R) -> Result<()>,
+{
+    let mut output = writer.entity::<R>()?;
+    while let Some(mut row) = rows.try_next().await? {
+        prepare(&mut row)?;
+        output.write(&row)?;
+    }
+    output.finish()?;
 
-    path.push("token_transactions.jsonl");
+    Ok(())
+}
+
+/// Temporary sibling of the bundle directory, removed unless committed
+struct StagingDir {
+    path: PathBuf,
+    target: PathBuf,
+    force: bool,
+    committed: bool,
+}
+
+impl StagingDir {
+    fn create(target: &Path, force: bool) -> anyhow::Result<Self> {
+        let name = target
+            .file_name()
+            .with_context(|| format!("Invalid export path {}", target.display()))?
+            .to_string_lossy();
+
+        if !force && is_taken(target)? {
+            anyhow::bail!(
+                "{} already exists, use --force to overwrite it",
+                target.display()
+            );
+        }
+
+        let path = target.with_file_name(format!(".{}.tmp-{}", name, Uuid::new_v4().to_simple()));
+        std::fs::create_dir_all(&path)?;
 
-    let mut output = File::create(path)?;
-    for token_transaction in token_transactions.iter() {
-        let token_transaction = serde_json::to_string(token_transaction)? + "\n";
-        output.write_all(token_transaction.as_bytes())?;
+        Ok(Self {
+            path,
+            target: target.to_path_buf(),
+            force,
+            committed: false,
+        })
     }
 
-    output.flush()?;
+    fn path(&self) -> &Path {
+        &self.path
+    }
 
-    Ok(())
-}
+    /// Moves the complete bundle into place, replacing an existing one if forced
+    fn commit(mut self) -> anyhow::Result<()> {
+        sync_dir(&self.path)?;
+        self.replace_target(&self.path)?;
+        self.committed = true;
+        Ok(())
+    }
 
-async fn export_addresses(
-    service_id: ServiceId,
-    sqlx_client: &SqlxClient,
-    mut path: PathBuf,
-    key: [u8; 32],
-) -> Result<()> {
-    let mut addresses = sqlx_client.get_all_addresses(service_id).await?;
+    /// Packs the complete bundle into a tar archive at the target path, the staging directory is dropped
+    fn commit_archive(self, compression: Compression) -> anyhow::Result<()> {
+        let mut archive = self.path.clone().into_os_string();
+        archive.push(".tar");
+        let archive = PathBuf::from(archive);
 
-    path.push("addresses.jsonl");
+        let result = pack_archive(&self.path, &archive, compression)
+            .and_then(|_| self.replace_target(&archive));
 
-    let mut output = File::create(path)?;
-    for address in addresses.iter_mut() {
-        let private_key = decrypt(&address.private_key, key, &address.id)?;
-        address.private_key = base64::encode(private_key);
-        address.balance = BigDecimal::from(0);
+        if result.is_err() {
+            let _ = std::fs::remove_file(&archive);
+        }
+        result
+    }
 
-        let address = serde_json::to_string(address)? + "\n";
+    /// Renames `path` to the target path, an existing bundle directory or file is only replaced if forced
+    fn replace_target(&self, path: &Path) -> anyhow::Result<()> {
+        let previous = match is_taken(&self.target)? {
+            true if !self.force => anyhow::bail!(
+                "{} appeared during export, use --force to overwrite it",
+                self.target.display()
+            ),
+            true => {
+                let mut previous = self.path.clone().into_os_string();
+                previous.push(".old");
+                std::fs::rename(&self.target, &previous)?;
+                Some(PathBuf::from(previous))
+            }
+            false => {
+                // Left by a user or a previous run, `rename` needs it gone
+                if self.target.is_dir() {
+                    std::fs::remove_dir(&self.target)?;
+                }
+                None
+            }
+        };
+
+        std::fs::rename(path, &self.target)?;
+
+        sync_parent(&self.target)?;
+        match previous {
+            Some(previous) if previous.is_dir() => std::fs::remove_dir_all(previous)?,
+            Some(previous) => std::fs::remove_file(previous)?,
+            None => {}
+        }
 
-        output.write_all(address.as_bytes())?;
+        Ok(())
     }
+}
 
-    output.flush()?;
+impl Drop for StagingDir {
+    fn drop(&mut self) {
+        if !self.committed {
+            let _ = std::fs::remove_dir_all(&self.path);
+        }
+    }
+}
 
+/// Whether `path` is a file, such as an archive, or a non-empty directory, which only `--force` replaces
+fn is_taken(path: &Path) -> anyhow::Result<bool> {
+    if !path.exists() {
+        return Ok(false);
+    }
+    if !path.is_dir() {
+        return Ok(true);
+    }
+    Ok(std::fs::read_dir(path)?.next().is_some())
+}
+
+fn sync_dir(path: &Path) -> anyhow::Result<()> {
+    File::open(path)?.sync_all()?;
     Ok(())
 }
 
-async fn export_token_owners(
-    service_id: ServiceId,
-    sqlx_client: &SqlxClient,
-    mut path: PathBuf,
-) -> Result<()> {
-    path.push("token_owners.jsonl");
-
-    let mut output = File::create(path)?;
-
-    let addresses = sqlx_client.get_all_addresses(service_id).await?;
-    for address in addresses.iter() {
-        if let Ok(token_owner) = sqlx_client
-            .get_token_owner_by_owner_account(address.workchain_id, &address.hex)
-            .await
-        {
-            let token_owner = serde_json::to_string(&token_owner)? + "\n";
-            output.write_all(token_owner.as_bytes())?;
-        }
+fn sync_parent(path: &Path) -> anyhow::Result<()> {
+    match path.parent() {
+        // An empty parent means the current directory
+        Some(parent) if parent.as_os_str().is_empty() => sync_dir(Path::new(".")),
+        Some(parent) => sync_dir(parent),
+        None => Ok(()),
+    }
+}
+
+/// Export path with the `.tar` extension matching `compression` appended unless it is there
+fn archive_path(path: &Path, compression: Compression) -> PathBuf {
+    let extension = format!(".tar{}", compression.extension());
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    if name.ends_with(&extension) || compression == Compression::Gzip && name.ends_with(".tgz") {
+        return path.to_path_buf();
     }
 
-    output.flush()?;
+    let mut path = path.as_os_str().to_os_string();
+    path.push(extension);
+    PathBuf::from(path)
+}
+
+/// Writes the manifest and bundle files of `dir` into a tar archive in import order,
+/// so that import reads it in a single pass
+fn pack_archive(dir: &Path, archive: &Path, compression: Compression) -> anyhow::Result<()> {
+    let mut names = std::fs::read_dir(dir)?
+        .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    // The manifest matches none of the bundle files and goes first
+    names.sort_by_key(|name| {
+        Entity::ALL
+            .iter()
+            .position(|entity| name.starts_with(&format!("{}.", entity)))
+    });
+
+    let output = compression.encoder(Box::new(File::create(archive)?))?;
+    let mut builder = tar::Builder::new(output);
+    builder.mode(tar::HeaderMode::Deterministic);
+    for name in names {
+        builder.append_path_with_name(dir.join(&name), &name)?;
+    }
+    builder.into_inner()?.finish_write()?;
 
     Ok(())
 }
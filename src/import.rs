@@ -1,112 +1,568 @@
 use std::fs::File;
-use std::io::BufRead;
+use std::path::Path;
 use std::path::PathBuf;
 use std::str::FromStr;
 
-use anyhow::Result;
+use anyhow::Context;
+use async_trait::async_trait;
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+use sqlx::postgres::PgDatabaseError;
+use zeroize::Zeroizing;
 
+use crate::bundle::*;
+use crate::error::*;
 use crate::models::*;
-use crate::sqlx_client::*;
+use crate::repository::*;
+use crate::sqlx_client::OnConflict;
 use crate::utils::*;
 
-pub async fn run_import(service_id: Option<String>, path: PathBuf, key: [u8; 32]) -> Result<()> {
-    let pool = get_pg_pool().await?;
-    let sqlx_client = SqlxClient::new(pool);
+pub const DEFAULT_BATCH_SIZE: usize = 10_000;
+
+#[derive(Debug, Clone)]
+pub struct ImportOptions {
+    /// Rows sent to the database in a single `COPY`
+    pub batch_size: usize,
+    /// Import everything in one transaction, rolled back on any error
+    pub atomic: bool,
+    /// Skip lines committed by a previous run according to its checkpoint
+    pub resume: bool,
+    /// Handling of addresses and transactions that already exist
+    pub on_conflict: OnConflict,
+    /// Passphrase of encrypted bundle files
+    pub transport_passphrase: Option<Zeroizing<String>>,
+    /// Key derivation `key` was produced with, compared with the exporting one
+    pub kdf: KdfParams,
+    /// Expected key check value of `key`, see [`key_check`]
+    pub key_check: Option<String>,
+    /// Imported entities, files of the others may be missing from the bundle
+    pub entities: Vec<Entity>,
+}
+
+impl Default for ImportOptions {
+    fn default() -> Self {
+        Self {
+            batch_size: DEFAULT_BATCH_SIZE,
+            atomic: false,
+            resume: false,
+            on_conflict: OnConflict::Fail,
+            transport_passphrase: None,
+            kdf: KdfParams::default(),
+            key_check: None,
+            entities: Entity::ALL.to_vec(),
+        }
+    }
+}
+
+pub async fn run_import<S: Repository>(
+    repository: &S,
+    service_id: Option<String>,
+    path: PathBuf,
+    key: Zeroizing<[u8; 32]>,
+    options: ImportOptions,
+) -> Result<ImportReport> {
+    // Refuse truncated or tampered bundles before touching the database
+    let bundle = BundleReader::open(
+        path,
+        options.transport_passphrase.as_ref().map(|p| p.as_str()),
+    )?;
+    let manifest = bundle.manifest();
+
+    // Partial bundles are imported as far as they go
+    let present = manifest.entities();
+    let entities = options
+        .entities
+        .iter()
+        .copied()
+        .filter(|entity| present.contains(entity))
+        .collect::<Vec<_>>();
+    if entities.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Bundle has none of the selected entities, it contains {}",
+            present
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+        .into());
+    }
+
+    bundle
+        .verify(&entities)
+        .context("Bundle verification failed")?;
+
+    // Services may be provisioned differently, so this is not an error
+    let mut report = ImportReport::default();
+    if manifest.kdf != options.kdf {
+        report.warnings.push(format!(
+            "bundle was exported with KDF {}, importing with {}",
+            manifest.kdf, options.kdf
+        ));
+    }
 
     let service_id = match service_id {
-        Some(service_id) => Some(ServiceId::from_str(&service_id)?),
+        Some(service_id) => Some(
+            ServiceId::from_str(&service_id)
+                .with_context(|| format!("Invalid service id {}", service_id))?,
+        ),
         None => None,
     };
 
-    import_addresses(&service_id, &sqlx_client, path.clone(), key).await?;
-    import_transactions(&service_id, &sqlx_client, path.clone()).await?;
-    import_token_owners(&sqlx_client, path.clone()).await?;
-    import_token_transactions(&service_id, &sqlx_client, path.clone()).await?;
+    // Keys encrypted with a different secret would be unusable by the service
+    check_key(
+        repository,
+        service_id.unwrap_or(manifest.service_id),
+        &key,
+        options.key_check.as_deref(),
+    )
+    .await?;
 
-    Ok(())
+    let checkpoint_path = Checkpoint::path_for(bundle.source().path());
+    let resume_from = match options.resume {
+        true => Checkpoint::load(&checkpoint_path)?,
+        false => None,
+    };
+    if let Some(checkpoint) = &resume_from {
+        checkpoint.check(&checkpoint_path, manifest)?;
+    }
+
+    let mut importer = Importer::new(
+        repository,
+        options.batch_size,
+        options.atomic,
+        options.on_conflict,
+    )
+    .with_checkpoints(manifest, checkpoint_path, resume_from);
+
+    let result = async {
+        for entity in entities {
+            match entity {
+                Entity::Addresses => {
+                    import_addresses(&service_id, &mut importer, &bundle, &key).await?
+                }
+                Entity::Transactions => {
+                    import_transactions(&service_id, &mut importer, &bundle).await?
+                }
+                Entity::TokenOwners => import_token_owners(&mut importer, &bundle).await?,
+                Entity::TokenTransactions => {
+                    import_token_transactions(&service_id, &mut importer, &bundle).await?
+                }
+            }
+        }
+        Ok::<_, Error>(())
+    }
+    .await;
+
+    result?;
+    importer.finish().await?;
+
+    Ok(report)
 }
 
-async fn import_transactions(
-    service_id: &Option<ServiceId>,
-    sqlx_client: &SqlxClient,
-    mut path: PathBuf,
-) -> Result<()> {
-    path.push("transactions.jsonl");
+/// Outcome of a successful import
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImportReport {
+    /// Differences from the exporting service which didn't stop the import, for the caller to show
+    pub warnings: Vec<String>,
+}
+
+/// Last committed position of an interrupted import
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// Export time of the bundle, which changes when it is exported again
+    pub exported_at: NaiveDateTime,
+    /// Bundle file being imported
+    pub file: String,
+    /// Hex encoded SHA-256 of `file` from the manifest
+    pub sha256: String,
+    /// Number of lines committed from `file`
+    pub line: usize,
+    /// Byte offset right after the last committed line
+    pub offset: u64,
+}
+
+impl Checkpoint {
+    /// Checkpoint file stored next to the bundle at `path`
+    pub fn path_for(path: &Path) -> PathBuf {
+        let mut name = path.file_name().unwrap_or_default().to_os_string();
+        name.push(".checkpoint.json");
+        path.with_file_name(name)
+    }
+
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let file = File::open(path)?;
+        let checkpoint = serde_json::from_reader(file)
+            .with_context(|| format!("Invalid checkpoint file {}", path.display()))?;
 
-    let file = File::open(path)?;
-    let reader = std::io::BufReader::new(file);
+        Ok(Some(checkpoint))
+    }
 
-    for line in reader.lines() {
-        let mut transaction: TransactionDb = serde_json::from_str(line?.as_str())?;
-        if let Some(service_id) = service_id {
-            transaction.service_id = *service_id;
+    /// Fails unless the checkpoint was written while importing the bundle of `manifest`
+    pub fn check(&self, path: &Path, manifest: &Manifest) -> Result<()> {
+        let sha256 = manifest
+            .files
+            .get(&self.file)
+            .map(|file| file.sha256.as_str());
+        if self.exported_at != manifest.exported_at || sha256 != Some(self.sha256.as_str()) {
+            return Err(anyhow::anyhow!(
+                "Checkpoint {} was written for {} of the bundle exported at {}, \
+                 which doesn't match the bundle exported at {}, remove it to import from the start",
+                path.display(),
+                self.file,
+                self.exported_at,
+                manifest.exported_at
+            )
+            .into());
         }
-        sqlx_client.create_transaction(transaction).await?;
+
+        Ok(())
+    }
+
+    fn store(&self, path: &Path) -> Result<()> {
+        let mut tmp = path.as_os_str().to_os_string();
+        tmp.push(".tmp");
+
+        std::fs::write(
+            &tmp,
+            serde_json::to_vec(self).context("Invalid checkpoint")?,
+        )?;
+        std::fs::rename(&tmp, path)?;
+
+        Ok(())
     }
+}
+
+async fn import_transactions<S: Repository>(
+    service_id: &Option<ServiceId>,
+    importer: &mut Importer<'_, S>,
+    bundle: &BundleReader,
+) -> Result<()> {
+    importer
+        .import_file(bundle, |transaction: &mut TransactionDb| {
+            if let Some(service_id) = service_id {
+                transaction.service_id = *service_id;
+            }
+            Ok(())
+        })
+        .await
+}
 
-    Ok(())
+async fn import_token_transactions<S: Repository>(
+    service_id: &Option<ServiceId>,
+    importer: &mut Importer<'_, S>,
+    bundle: &BundleReader,
+) -> Result<()> {
+    importer
+        .import_file(bundle, |token_transaction: &mut TokenTransactionDb| {
+            if let Some(service_id) = service_id {
+                token_transaction.service_id = *service_id;
+            }
+            Ok(())
+        })
+        .await
 }
 
-async fn import_token_transactions(
+async fn import_addresses<S: Repository>(
     service_id: &Option<ServiceId>,
-    sqlx_client: &SqlxClient,
-    mut path: PathBuf,
+    importer: &mut Importer<'_, S>,
+    bundle: &BundleReader,
+    key: &[u8; 32],
+) -> Result<()> {
+    importer
+        .import_file(bundle, |address: &mut AddressDb| {
+            if let Some(service_id) = service_id {
+                address.service_id = *service_id;
+            }
+            address.private_key = encrypt(&address.private_key, key, &address.id)?;
+            Ok(())
+        })
+        .await
+}
+
+async fn import_token_owners<S: Repository>(
+    importer: &mut Importer<'_, S>,
+    bundle: &BundleReader,
 ) -> Result<()> {
-    path.push("token_transactions.jsonl");
+    importer
+        .import_file(bundle, |_: &mut TokenOwnerDb| Ok(()))
+        .await
+}
 
-    let file = File::open(path)?;
-    let reader = std::io::BufReader::new(file);
+/// Rows written by an [`Importer`], read from a bundle file or streamed from another database
+#[async_trait(?Send)]
+pub(crate) trait RowSource<R> {
+    /// Name failed rows are reported with
+    fn name(&self) -> &str;
 
-    for line in reader.lines() {
-        let mut token_transaction: TokenTransactionDb = serde_json::from_str(line?.as_str())?;
-        if let Some(service_id) = service_id {
-            token_transaction.service_id = *service_id;
-        }
-        sqlx_client
-            .create_token_transaction(token_transaction)
-            .await?;
+    /// Number of the last read row
+    fn line(&self) -> usize;
+
+    /// Byte offset right after the last read row, set for sources imports can be resumed from
+    fn offset(&self) -> Option<u64> {
+        None
     }
 
-    Ok(())
+    async fn next_row(&mut self) -> Option<Result<R>>;
 }
 
-async fn import_addresses(
-    service_id: &Option<ServiceId>,
-    sqlx_client: &SqlxClient,
-    mut path: PathBuf,
-    key: [u8; 32],
-) -> Result<()> {
-    path.push("addresses.jsonl");
+#[async_trait(?Send)]
+impl<R: BundleRow> RowSource<R> for EntityReader<R> {
+    fn name(&self) -> &str {
+        EntityReader::name(self)
+    }
 
-    let file = File::open(path)?;
-    let reader = std::io::BufReader::new(file);
+    fn line(&self) -> usize {
+        EntityReader::line(self)
+    }
 
-    let mut addresses = Vec::new();
-    for line in reader.lines() {
-        let mut address: AddressDb = serde_json::from_str(line?.as_str())?;
-        if let Some(service_id) = service_id {
-            address.service_id = *service_id;
-        }
-        let private_key = encrypt(&address.private_key, key, &address.id)?;
-        address.private_key = private_key;
-        addresses.push(address);
+    fn offset(&self) -> Option<u64> {
+        Some(EntityReader::offset(self))
     }
 
-    sqlx_client.create_addresses(addresses).await?;
+    async fn next_row(&mut self) -> Option<Result<R>> {
+        self.next()
+    }
+}
+
+/// Writes batches either in their own transactions or in a single one for atomic imports and copies
+pub(crate) struct Importer<'a, S: Repository> {
+    repository: &'a S,
+    tx: Option<S::Transaction>,
+    batch_size: usize,
+    atomic: bool,
+    on_conflict: OnConflict,
+    checkpoints: Option<Checkpoints<'a>>,
+}
 
-    Ok(())
+/// Checkpoints of a bundle import, stored after every committed batch
+struct Checkpoints<'a> {
+    manifest: &'a Manifest,
+    path: PathBuf,
+    /// Checkpoint of the interrupted import, until its file is reached
+    resume_from: Option<Checkpoint>,
 }
 
-async fn import_token_owners(sqlx_client: &SqlxClient, mut path: PathBuf) -> Result<()> {
-    path.push("token_owners.jsonl");
+impl<'a, S: Repository> Importer<'a, S> {
+    pub(crate) fn new(
+        repository: &'a S,
+        batch_size: usize,
+        atomic: bool,
+        on_conflict: OnConflict,
+    ) -> Self {
+        Self {
+            repository,
+            tx: None,
+            batch_size: batch_size.max(1),
+            atomic,
+            on_conflict,
+            checkpoints: None,
+        }
+    }
+
+    /// Stores checkpoints of the bundle of `manifest` at `path`, skipping rows up to `resume_from`
+    fn with_checkpoints(
+        mut self,
+        manifest: &'a Manifest,
+        path: PathBuf,
+        resume_from: Option<Checkpoint>,
+    ) -> Self {
+        self.checkpoints = Some(Checkpoints {
+            manifest,
+            path,
+            resume_from,
+        });
+        self
+    }
+
+    async fn import_file<R, F>(&mut self, bundle: &BundleReader, prepare: F) -> Result<()>
+    where
+        R: RepositoryRow + BundleRow,
+        F: FnMut(&mut R) -> Result<()>,
+    {
+        let mut reader = bundle.entity::<R>()?;
+
+        // Files are imported in a fixed order, so everything before the checkpoint is done
+        if let Some(checkpoints) = &mut self.checkpoints {
+            if let Some(checkpoint) = &checkpoints.resume_from {
+                if checkpoint.file != reader.name() {
+                    return Ok(());
+                }
+                reader.skip_to(checkpoint.line, checkpoint.offset)?;
+                checkpoints.resume_from = None;
+            }
+        }
+
+        self.import(&mut reader, prepare).await?;
+        Ok(())
+    }
+
+    /// Writes all rows of `source` after `prepare`, returns the number of inserted or updated ones
+    pub(crate) async fn import<R, F>(
+        &mut self,
+        source: &mut impl RowSource<R>,
+        mut prepare: F,
+    ) -> Result<u64>
+    where
+        R: RepositoryRow + BundleRow,
+        F: FnMut(&mut R) -> Result<()>,
+    {
+        let on_conflict = match R::ENTITY {
+            // Token owners are shared between services, existing ones are always kept
+            Entity::TokenOwners => OnConflict::Skip,
+            _ => self.on_conflict,
+        };
+
+        let mut rows = Vec::with_capacity(self.batch_size);
+        let mut first_line = source.line() + 1;
+        let mut written = 0;
+        while let Some(row) = source.next_row().await {
+            let mut row = row?;
+            prepare(&mut row).map_err(|e| Error::Row {
+                file: source.name().to_owned(),
+                line: source.line(),
+                source: Box::new(e),
+            })?;
+            rows.push(row);
 
-    let file = File::open(path)?;
-    let reader = std::io::BufReader::new(file);
+            if rows.len() >= self.batch_size {
+                written += self.copy(first_line, &rows, source, on_conflict).await?;
+                rows.clear();
+                first_line = source.line() + 1;
+            }
+        }
 
-    for line in reader.lines() {
-        let token_owner: TokenOwnerDb = serde_json::from_str(line?.as_str())?;
-        sqlx_client.create_token_owner(token_owner).await?;
+        written += self.copy(first_line, &rows, source, on_conflict).await?;
+        Ok(written)
     }
 
-    Ok(())
+    /// Writes rows ending at the current position of `source`, which is saved as checkpoint once committed
+    async fn copy<R: RepositoryRow + BundleRow>(
+        &mut self,
+        first_line: usize,
+        rows: &[R],
+        source: &impl RowSource<R>,
+        on_conflict: OnConflict,
+    ) -> Result<u64> {
+        if rows.is_empty() {
+            return Ok(0);
+        }
+
+        let mut tx = match self.tx.take() {
+            Some(tx) => tx,
+            None => self.repository.begin().await?,
+        };
+
+        let written = match R::insert(self.repository, &mut tx, rows, on_conflict).await {
+            Ok(written) => written,
+            Err(e) => return Err(batch_error(e, source.name(), first_line, rows)),
+        };
+
+        if self.atomic {
+            self.tx = Some(tx);
+            return Ok(written);
+        }
+
+        self.repository.commit(tx).await?;
+        if let (Some(checkpoints), Some(offset)) = (&self.checkpoints, source.offset()) {
+            let manifest = checkpoints.manifest;
+            Checkpoint {
+                exported_at: manifest.exported_at,
+                file: source.name().to_owned(),
+                sha256: manifest
+                    .files
+                    .get(source.name())
+                    .map(|file| file.sha256.clone())
+                    .unwrap_or_default(),
+                line: source.line(),
+                offset,
+            }
+            .store(&checkpoints.path)?;
+        }
+
+        Ok(written)
+    }
+
+    pub(crate) async fn finish(self) -> Result<()> {
+        if let Some(checkpoint) = self
+            .checkpoints
+            .as_ref()
+            .and_then(|checkpoints| checkpoints.resume_from.as_ref())
+        {
+            return Err(anyhow::anyhow!(
+                "Checkpoint file {} is not in the bundle",
+                checkpoint.file
+            )
+            .into());
+        }
+
+        if let Some(tx) = self.tx {
+            self.repository.commit(tx).await?;
+        }
+
+        if let Some(checkpoints) = &self.checkpoints {
+            if checkpoints.path.exists() {
+                std::fs::remove_file(&checkpoints.path)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Adds the file and line of the failed row to a batch error, using the `COPY` context when available
+fn batch_error<R: BundleRow>(error: Error, name: &str, first_line: usize, rows: &[R]) -> Error {
+    let error = match error {
+        // The violated key is all that is known about a duplicate, so the row is found by it
+        Error::DuplicateRow {
+            entity, key, id, ..
+        } => {
+            let line = rows
+                .iter()
+                .position(|row| row.has_key(&key, &id))
+                .map(|index| first_line + index);
+            return Error::DuplicateRow {
+                entity,
+                key,
+                id,
+                file: Some(name.to_owned()),
+                line,
+            };
+        }
+        error @ Error::Database(_) => error,
+        error => return error,
+    };
+
+    let copy_line = match &error {
+        Error::Database(error) => error
+            .as_database_error()
+            .and_then(|e| e.try_downcast_ref::<PgDatabaseError>())
+            .and_then(|e| e.r#where())
+            .and_then(|context| {
+                let (_, line) = context.split_once(", line ")?;
+                line.split(|c: char| !c.is_ascii_digit())
+                    .next()?
+                    .parse::<usize>()
+                    .ok()
+            }),
+        _ => None,
+    };
+
+    match copy_line {
+        Some(line) if line >= 1 && line <= rows.len() => Error::Row {
+            file: name.to_owned(),
+            line: first_line + line - 1,
+            source: Box::new(error),
+        },
+        _ => Error::Batch {
+            file: name.to_owned(),
+            first_line,
+            last_line: first_line + rows.len() - 1,
+            source: Box::new(error),
+        },
+    }
 }
@@ -2,8 +2,17 @@
 #![allow(clippy::too_many_arguments)]
 #![allow(clippy::inconsistent_struct_constructor)]
 
+pub mod bundle;
+pub mod copy;
+pub mod error;
 pub mod export;
 pub mod import;
 pub mod models;
+pub mod purge;
+pub mod rekey;
+pub mod repository;
 pub mod sqlx_client;
 pub mod utils;
+pub mod verify;
+
+pub use self::error::{Error, Result};
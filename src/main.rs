@@ -1,14 +1,20 @@
-use std::convert::TryInto;
 use std::path::PathBuf;
 use std::str::FromStr;
 
-use anyhow::{Context, Result};
+use anyhow::Result;
 use argh::FromArgs;
-use argon2::password_hash::PasswordHasher;
+use chrono::{DateTime, NaiveDateTime};
+use zeroize::Zeroizing;
 
+use ton_api_utility::copy::*;
 use ton_api_utility::export::*;
 use ton_api_utility::import::*;
 use ton_api_utility::models::*;
+use ton_api_utility::purge::*;
+use ton_api_utility::rekey::*;
+use ton_api_utility::sqlx_client::{OnConflict, SqlxClient};
+use ton_api_utility::utils::{derive_key, get_pg_pool, Compression, SecretSource};
+use ton_api_utility::verify::*;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -18,6 +24,10 @@ async fn run(app: App) -> Result<()> {
     match app.command {
         Subcommand::Export(run) => run.execute().await,
         Subcommand::Import(run) => run.execute().await,
+        Subcommand::Verify(run) => run.execute().await,
+        Subcommand::Rekey(run) => run.execute().await,
+        Subcommand::Copy(run) => run.execute().await,
+        Subcommand::Purge(run) => run.execute().await,
     }
 }
 
@@ -33,6 +43,10 @@ struct App {
 enum Subcommand {
     Export(CmdExport),
     Import(CmdImport),
+    Verify(CmdVerify),
+    Rekey(CmdRekey),
+    Copy(CmdCopy),
+    Purge(CmdPurge),
 }
 
 #[derive(Debug, PartialEq, FromArgs)]
@@ -46,45 +60,137 @@ struct CmdExport {
     /// export path
     #[argh(option, short = 'p')]
     path: Option<String>,
-    /// secret
+    /// secret, visible in shell history and process list, prefer --key-from
     #[argh(option, short = 'k')]
-    key: String,
+    key: Option<String>,
+    /// read the secret from env:NAME, file:PATH, fd:N or prompt (default)
+    #[argh(option)]
+    key_from: Option<SecretSource>,
     /// salt
     #[argh(option, short = 's')]
     salt: String,
+    /// argon2 variant and costs, e.g. argon2id,m=4096,t=3,p=1 (default)
+    #[argh(option, default = "KdfParams::default()")]
+    kdf: KdfParams,
+    /// export rows updated at or after this RFC3339 time
+    #[argh(option, from_str_fn(parse_timestamp))]
+    since: Option<NaiveDateTime>,
+    /// export rows updated before this RFC3339 time
+    #[argh(option, from_str_fn(parse_timestamp))]
+    until: Option<NaiveDateTime>,
+    /// previous bundle whose watermark is used as --since
+    #[argh(option)]
+    incremental_from: Option<String>,
+    /// file of account addresses to export, one hex or base64url address per line
+    #[argh(option)]
+    accounts: Option<String>,
+    /// expected key check value, as recorded in manifest.json
+    #[argh(option)]
+    key_check: Option<String>,
+    /// overwrite an existing bundle at the export path
+    #[argh(switch)]
+    force: bool,
+    /// file format: jsonl, parquet or csv, which can't be imported (default jsonl)
+    #[argh(option, default = "BundleFormat::Jsonl")]
+    format: BundleFormat,
+    /// keep private keys in csv files, which exclude them by default
+    #[argh(switch)]
+    include_private_keys: bool,
+    /// compress bundle files: none, gzip or zstd (default none)
+    #[argh(option, default = "Compression::None")]
+    compress: Compression,
+    /// write a single .tar archive, compressed as a whole with --compress
+    #[argh(switch)]
+    archive: bool,
+    /// export only these entities, e.g. addresses,token_owners
+    #[argh(option, from_str_fn(parse_entities))]
+    only: Option<Vec<Entity>>,
+    /// export everything but these entities
+    #[argh(option, from_str_fn(parse_entities))]
+    skip: Option<Vec<Entity>>,
+    /// encrypt the addresses file with this passphrase
+    #[argh(option)]
+    transport_passphrase: Option<String>,
+    /// read the transport passphrase from env:NAME, file:PATH, fd:N or prompt
+    #[argh(option)]
+    transport_passphrase_from: Option<SecretSource>,
 }
 
 impl CmdExport {
     async fn execute(self) -> Result<()> {
         let service_id = ServiceId::from_str(&self.id)?;
 
-        let mut options = argon2::ParamsBuilder::default();
-        let options = options
-            .output_len(32) //chacha key size
-            .and_then(|x| x.clone().params())
-            .unwrap();
+        if self.include_private_keys && self.format != BundleFormat::Csv {
+            anyhow::bail!(
+                "--include-private-keys only applies to --format csv, jsonl always has them and parquet never"
+            );
+        }
+        if self.format == BundleFormat::Parquet
+            && (self.transport_passphrase.is_some() || self.transport_passphrase_from.is_some())
+        {
+            anyhow::bail!(
+                "--transport-passphrase doesn't apply to --format parquet, which has no private keys"
+            );
+        }
 
-        // Argon2 with default params (Argon2id v19)
-        let argon2 =
-            argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, options);
-
-        let key = argon2
-            .hash_password(self.key.as_bytes(), &self.salt)
-            .unwrap()
-            .hash
-            .context("No hash")?
-            .as_bytes()
-            .try_into()?;
+        let secret = read_secret(self.key, self.key_from, "secret")?;
+        let key = derive_key(&secret, &self.salt, &self.kdf)?;
 
         let path = match self.path {
             Some(path) => PathBuf::from_str(&path)?,
             None => PathBuf::from_str("./data")?,
         };
 
-        // Prepare data folder
-        std::fs::create_dir_all(&path)?;
+        let mut accounts = match self.accounts {
+            Some(accounts) => Some(AccountAddress::load_list(&PathBuf::from_str(&accounts)?)?),
+            None => None,
+        };
+
+        let since = match self.incremental_from {
+            Some(_) if self.since.is_some() => {
+                anyhow::bail!("--since and --incremental-from are mutually exclusive")
+            }
+            Some(previous) => {
+                let manifest = Manifest::load(&BundleSource::new(PathBuf::from_str(&previous)?)?)?;
+                if manifest.service_id != service_id {
+                    anyhow::bail!(
+                        "{} is an export of service {}",
+                        previous,
+                        manifest.service_id
+                    );
+                }
+                // Deltas of a partial bundle cover the same accounts unless given explicitly
+                if accounts.is_none() {
+                    accounts = manifest.accounts;
+                }
+                manifest.watermark
+            }
+            None => self.since,
+        };
 
-        run_export(service_id, path, key).await
+        let options = ExportOptions {
+            filter: ExportFilter {
+                since,
+                until: self.until,
+                accounts,
+            },
+            transport_passphrase: read_optional_secret(
+                self.transport_passphrase,
+                self.transport_passphrase_from,
+                "transport passphrase",
+            )?,
+            kdf: self.kdf,
+            key_check: self.key_check,
+            force: self.force,
+            format: self.format,
+            include_private_keys: self.include_private_keys,
+            compression: self.compress,
+            archive: self.archive,
+            entities: Entity::select(self.only.as_deref(), self.skip.as_deref())?,
+        };
+
+        let sqlx_client = SqlxClient::new(get_pg_pool().await?);
+        Ok(run_export(&sqlx_client, service_id, path, key, options).await?)
     }
 }
 
@@ -99,41 +205,432 @@ struct CmdImport {
     /// export path
     #[argh(option, short = 'p')]
     path: Option<String>,
-    /// secret
+    /// secret, visible in shell history and process list, prefer --key-from
     #[argh(option, short = 'k')]
-    key: String,
+    key: Option<String>,
+    /// read the secret from env:NAME, file:PATH, fd:N or prompt (default)
+    #[argh(option)]
+    key_from: Option<SecretSource>,
     /// salt
     #[argh(option, short = 's')]
     salt: String,
+    /// argon2 variant and costs, e.g. argon2id,m=4096,t=3,p=1 (default)
+    #[argh(option, default = "KdfParams::default()")]
+    kdf: KdfParams,
+    /// rows per COPY batch (default 10000)
+    #[argh(option, short = 'b', default = "DEFAULT_BATCH_SIZE")]
+    batch_size: usize,
+    /// import everything in a single transaction
+    #[argh(switch)]
+    atomic: bool,
+    /// continue an interrupted import from its checkpoint
+    #[argh(switch)]
+    resume: bool,
+    /// existing rows handling: fail, skip or update (default fail)
+    #[argh(option, default = "OnConflict::Fail")]
+    on_conflict: OnConflict,
+    /// expected key check value, as recorded in manifest.json
+    #[argh(option)]
+    key_check: Option<String>,
+    /// import only these entities, e.g. addresses,token_owners
+    #[argh(option, from_str_fn(parse_entities))]
+    only: Option<Vec<Entity>>,
+    /// import everything but these entities, their files may be missing
+    #[argh(option, from_str_fn(parse_entities))]
+    skip: Option<Vec<Entity>>,
+    /// passphrase of the encrypted addresses file
+    #[argh(option)]
+    transport_passphrase: Option<String>,
+    /// read the transport passphrase from env:NAME, file:PATH, fd:N or prompt
+    #[argh(option)]
+    transport_passphrase_from: Option<SecretSource>,
 }
 
 impl CmdImport {
     async fn execute(self) -> Result<()> {
         let service_id = self.id;
 
-        let mut options = argon2::ParamsBuilder::default();
-        let options = options
-            .output_len(32) //chacha key size
-            .and_then(|x| x.clone().params())
-            .unwrap();
+        let secret = read_secret(self.key, self.key_from, "secret")?;
+        let key = derive_key(&secret, &self.salt, &self.kdf)?;
+
+        let path = match self.path {
+            Some(path) => PathBuf::from_str(&path)?,
+            None => PathBuf::from_str("./data")?,
+        };
+
+        let options = ImportOptions {
+            batch_size: self.batch_size,
+            atomic: self.atomic,
+            resume: self.resume,
+            on_conflict: self.on_conflict,
+            transport_passphrase: read_optional_secret(
+                self.transport_passphrase,
+                self.transport_passphrase_from,
+                "transport passphrase",
+            )?,
+            kdf: self.kdf,
+            key_check: self.key_check,
+            entities: Entity::select(self.only.as_deref(), self.skip.as_deref())?,
+        };
+
+        let atomic = options.atomic;
+        let sqlx_client = SqlxClient::new(get_pg_pool().await?);
+        match run_import(&sqlx_client, service_id, path, key, options).await {
+            Ok(report) => {
+                for warning in report.warnings {
+                    eprintln!("Warning: {}", warning);
+                }
+                Ok(())
+            }
+            Err(e) if atomic => Err(anyhow::Error::from(e).context("Import rolled back")),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, FromArgs)]
+/// Compare an exported bundle
+/// with the rows in DB
+#[argh(subcommand, name = "verify")]
+struct CmdVerify {
+    /// service id the bundle was imported as (defaults to the exported one)
+    #[argh(option, short = 'i')]
+    id: Option<String>,
+    /// export path
+    #[argh(option, short = 'p')]
+    path: Option<String>,
+    /// secret, visible in shell history and process list, prefer --key-from
+    #[argh(option, short = 'k')]
+    key: Option<String>,
+    /// read the secret from env:NAME, file:PATH, fd:N or prompt (default)
+    #[argh(option)]
+    key_from: Option<SecretSource>,
+    /// salt
+    #[argh(option, short = 's')]
+    salt: String,
+    /// argon2 variant and costs, e.g. argon2id,m=4096,t=3,p=1 (default)
+    #[argh(option, default = "KdfParams::default()")]
+    kdf: KdfParams,
+    /// passphrase of the encrypted addresses file
+    #[argh(option)]
+    transport_passphrase: Option<String>,
+    /// read the transport passphrase from env:NAME, file:PATH, fd:N or prompt
+    #[argh(option)]
+    transport_passphrase_from: Option<SecretSource>,
+}
 
-        // Argon2 with default params (Argon2id v19)
-        let argon2 =
-            argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, options);
+impl CmdVerify {
+    async fn execute(self) -> Result<()> {
+        let service_id = match self.id {
+            Some(id) => Some(ServiceId::from_str(&id)?),
+            None => None,
+        };
 
-        let key = argon2
-            .hash_password(self.key.as_bytes(), &self.salt)
-            .unwrap()
-            .hash
-            .context("No hash")?
-            .as_bytes()
-            .try_into()?;
+        let secret = read_secret(self.key, self.key_from, "secret")?;
+        let key = derive_key(&secret, &self.salt, &self.kdf)?;
 
         let path = match self.path {
             Some(path) => PathBuf::from_str(&path)?,
             None => PathBuf::from_str("./data")?,
         };
 
-        run_import(service_id, path, key).await
+        let transport_passphrase = read_optional_secret(
+            self.transport_passphrase,
+            self.transport_passphrase_from,
+            "transport passphrase",
+        )?;
+
+        let report = run_verify(
+            service_id,
+            path,
+            key,
+            transport_passphrase.as_ref().map(|p| p.as_str()),
+        )
+        .await?;
+        print!("{}", report);
+
+        if !report.is_consistent() {
+            anyhow::bail!("Bundle and database diverge");
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, FromArgs)]
+/// Re-encrypt private keys of addresses
+/// with a new secret and salt
+#[argh(subcommand, name = "rekey")]
+struct CmdRekey {
+    /// service id
+    #[argh(option, short = 'i')]
+    id: String,
+    /// current secret, prefer --key-from
+    #[argh(option, short = 'k')]
+    key: Option<String>,
+    /// read the current secret from env:NAME, file:PATH, fd:N or prompt (default)
+    #[argh(option)]
+    key_from: Option<SecretSource>,
+    /// current salt
+    #[argh(option, short = 's')]
+    salt: String,
+    /// current argon2 variant and costs (default argon2id,m=4096,t=3,p=1)
+    #[argh(option, default = "KdfParams::default()")]
+    kdf: KdfParams,
+    /// new secret, prefer --new-key-from
+    #[argh(option)]
+    new_key: Option<String>,
+    /// read the new secret from env:NAME, file:PATH, fd:N or prompt (default)
+    #[argh(option)]
+    new_key_from: Option<SecretSource>,
+    /// new salt
+    #[argh(option)]
+    new_salt: String,
+    /// new argon2 variant and costs (defaults to --kdf)
+    #[argh(option)]
+    new_kdf: Option<KdfParams>,
+}
+
+impl CmdRekey {
+    async fn execute(self) -> Result<()> {
+        let service_id = ServiceId::from_str(&self.id)?;
+
+        let secret = read_secret(self.key, self.key_from, "current secret")?;
+        let old_key = derive_key(&secret, &self.salt, &self.kdf)?;
+        let new_secret = read_secret(self.new_key, self.new_key_from, "new secret")?;
+        let new_key = derive_key(
+            &new_secret,
+            &self.new_salt,
+            &self.new_kdf.unwrap_or(self.kdf),
+        )?;
+
+        let updated = run_rekey(service_id, old_key, new_key).await?;
+        println!("Re-encrypted {} private keys", updated);
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, FromArgs)]
+/// Copy addresses and transactions
+/// from one DB or service to another
+#[argh(subcommand, name = "copy")]
+struct CmdCopy {
+    /// source database url
+    #[argh(option)]
+    source_url: String,
+    /// target database url, another database than the source one
+    #[argh(option)]
+    target_url: String,
+    /// source service id
+    #[argh(option, short = 'i')]
+    id: String,
+    /// target service id (defaults to the source one)
+    #[argh(option)]
+    target_id: Option<String>,
+    /// source secret, prefer --key-from
+    #[argh(option, short = 'k')]
+    key: Option<String>,
+    /// read the source secret from env:NAME, file:PATH, fd:N or prompt (default)
+    #[argh(option)]
+    key_from: Option<SecretSource>,
+    /// source salt
+    #[argh(option, short = 's')]
+    salt: String,
+    /// source argon2 variant and costs (default argon2id,m=4096,t=3,p=1)
+    #[argh(option, default = "KdfParams::default()")]
+    kdf: KdfParams,
+    /// expected key check value of the source secret
+    #[argh(option)]
+    key_check: Option<String>,
+    /// target secret (defaults to the source one), prefer --target-key-from
+    #[argh(option)]
+    target_key: Option<String>,
+    /// read the target secret from env:NAME, file:PATH, fd:N or prompt
+    #[argh(option)]
+    target_key_from: Option<SecretSource>,
+    /// target salt (defaults to the source one)
+    #[argh(option)]
+    target_salt: Option<String>,
+    /// target argon2 variant and costs (defaults to --kdf)
+    #[argh(option)]
+    target_kdf: Option<KdfParams>,
+    /// expected key check value of the target secret
+    #[argh(option)]
+    target_key_check: Option<String>,
+    /// copy rows updated at or after this RFC3339 time
+    #[argh(option, from_str_fn(parse_timestamp))]
+    since: Option<NaiveDateTime>,
+    /// copy rows updated before this RFC3339 time
+    #[argh(option, from_str_fn(parse_timestamp))]
+    until: Option<NaiveDateTime>,
+    /// file of account addresses to copy, one hex or base64url address per line
+    #[argh(option)]
+    accounts: Option<String>,
+    /// copy only these entities, e.g. addresses,token_owners
+    #[argh(option, from_str_fn(parse_entities))]
+    only: Option<Vec<Entity>>,
+    /// copy everything but these entities
+    #[argh(option, from_str_fn(parse_entities))]
+    skip: Option<Vec<Entity>>,
+    /// rows per COPY batch (default 10000)
+    #[argh(option, short = 'b', default = "DEFAULT_BATCH_SIZE")]
+    batch_size: usize,
+    /// copy everything in a single transaction
+    #[argh(switch)]
+    atomic: bool,
+    /// existing rows handling: fail, skip or update (default fail)
+    #[argh(option, default = "OnConflict::Fail")]
+    on_conflict: OnConflict,
+}
+
+impl CmdCopy {
+    async fn execute(self) -> Result<()> {
+        let service_id = ServiceId::from_str(&self.id)?;
+        let target_id = match self.target_id {
+            Some(id) => ServiceId::from_str(&id)?,
+            None => service_id,
+        };
+
+        let secret = read_secret(self.key, self.key_from, "source secret")?;
+        let key = derive_key(&secret, &self.salt, &self.kdf)?;
+        let target_secret =
+            read_optional_secret(self.target_key, self.target_key_from, "target secret")?;
+        let target_key = derive_key(
+            target_secret.as_ref().unwrap_or(&secret),
+            self.target_salt.as_ref().unwrap_or(&self.salt),
+            &self.target_kdf.unwrap_or(self.kdf),
+        )?;
+
+        let accounts = match self.accounts {
+            Some(accounts) => Some(AccountAddress::load_list(&PathBuf::from_str(&accounts)?)?),
+            None => None,
+        };
+
+        let options = CopyOptions {
+            filter: ExportFilter {
+                since: self.since,
+                until: self.until,
+                accounts,
+            },
+            entities: Entity::select(self.only.as_deref(), self.skip.as_deref())?,
+            batch_size: self.batch_size,
+            atomic: self.atomic,
+            on_conflict: self.on_conflict,
+            source_key_check: self.key_check,
+            target_key_check: self.target_key_check,
+        };
+
+        let source = CopyEndpoint {
+            database_url: self.source_url,
+            service_id,
+            key,
+        };
+        let target = CopyEndpoint {
+            database_url: self.target_url,
+            service_id: target_id,
+            key: target_key,
+        };
+
+        let report = run_copy(source, target, options).await?;
+        print!("{}", report);
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, FromArgs)]
+/// Delete all rows of a service
+/// after a verified export
+#[argh(subcommand, name = "purge")]
+struct CmdPurge {
+    /// service id
+    #[argh(option, short = 'i')]
+    id: String,
+    /// path of a full export of the service
+    #[argh(option, short = 'p')]
+    path: String,
+    /// secret, visible in shell history and process list, prefer --key-from
+    #[argh(option, short = 'k')]
+    key: Option<String>,
+    /// read the secret from env:NAME, file:PATH, fd:N or prompt (default)
+    #[argh(option)]
+    key_from: Option<SecretSource>,
+    /// salt
+    #[argh(option, short = 's')]
+    salt: String,
+    /// argon2 variant and costs, e.g. argon2id,m=4096,t=3,p=1 (default)
+    #[argh(option, default = "KdfParams::default()")]
+    kdf: KdfParams,
+    /// passphrase of the encrypted addresses file
+    #[argh(option)]
+    transport_passphrase: Option<String>,
+    /// read the transport passphrase from env:NAME, file:PATH, fd:N or prompt
+    #[argh(option)]
+    transport_passphrase_from: Option<SecretSource>,
+    /// print the number of rows to delete and roll back
+    #[argh(switch)]
+    dry_run: bool,
+}
+
+impl CmdPurge {
+    async fn execute(self) -> Result<()> {
+        let service_id = ServiceId::from_str(&self.id)?;
+
+        let secret = read_secret(self.key, self.key_from, "secret")?;
+        let key = derive_key(&secret, &self.salt, &self.kdf)?;
+
+        let transport_passphrase = read_optional_secret(
+            self.transport_passphrase,
+            self.transport_passphrase_from,
+            "transport passphrase",
+        )?;
+
+        let report = run_purge(
+            service_id,
+            PathBuf::from_str(&self.path)?,
+            key,
+            transport_passphrase.as_ref().map(|p| p.as_str()),
+            self.dry_run,
+        )
+        .await?;
+        print!("{}", report);
+
+        Ok(())
+    }
+}
+
+fn parse_timestamp(value: &str) -> Result<NaiveDateTime, String> {
+    DateTime::parse_from_rfc3339(value)
+        .map(|timestamp| timestamp.naive_utc())
+        .map_err(|e| format!("Invalid RFC3339 timestamp `{}`: {}", value, e))
+}
+
+fn parse_entities(value: &str) -> Result<Vec<Entity>, String> {
+    Entity::parse_list(value).map_err(|e| e.to_string())
+}
+
+/// Secret given inline or read from `source`, prompted for when neither is set
+fn read_secret(
+    value: Option<String>,
+    source: Option<SecretSource>,
+    name: &str,
+) -> Result<Zeroizing<String>> {
+    match (value, source) {
+        (Some(_), Some(_)) => anyhow::bail!("The {} is given both inline and with a source", name),
+        (Some(value), None) => Ok(Zeroizing::new(value)),
+        (None, source) => source.unwrap_or(SecretSource::Prompt).read(name),
+    }
+}
+
+/// Same as [`read_secret`], but nothing is read when neither is set
+fn read_optional_secret(
+    value: Option<String>,
+    source: Option<SecretSource>,
+    name: &str,
+) -> Result<Option<Zeroizing<String>>> {
+    match (value, source) {
+        (None, None) => Ok(None),
+        (value, source) => read_secret(value, source, name).map(Some),
     }
 }
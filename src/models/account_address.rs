@@ -0,0 +1,119 @@
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+/// Account address in the `workchain_id` and `hex` form it is stored in
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct AccountAddress {
+    pub workchain_id: i32,
+    pub hex: String,
+}
+
+impl AccountAddress {
+    /// Reads addresses from a file, one per line, skipping empty lines and `#` comments
+    pub fn load_list(path: &Path) -> Result<Vec<Self>> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+
+        let mut addresses = content
+            .lines()
+            .enumerate()
+            .map(|(index, line)| (index, line.split('#').next().unwrap_or_default().trim()))
+            .filter(|(_, line)| !line.is_empty())
+            .map(|(index, line)| {
+                AccountAddress::from_str(line)
+                    .with_context(|| format!("{}: line {}", path.display(), index + 1))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if addresses.is_empty() {
+            anyhow::bail!("{} has no addresses", path.display());
+        }
+
+        addresses.sort();
+        addresses.dedup();
+        Ok(addresses)
+    }
+
+    fn from_base64(s: &str) -> Result<Self> {
+        // Wallets show both alphabets
+        let bytes = base64::decode_config(s, base64::URL_SAFE)
+            .or_else(|_| base64::decode_config(s, base64::STANDARD))
+            .map_err(|e| anyhow::anyhow!("Invalid base64url address `{}`: {}", s, e))?;
+        if bytes.len() != 36 {
+            anyhow::bail!("Invalid base64url address `{}`: wrong length", s);
+        }
+
+        let checksum = u16::from_be_bytes([bytes[34], bytes[35]]);
+        if crc16(&bytes[..34]) != checksum {
+            anyhow::bail!("Invalid base64url address `{}`: checksum mismatch", s);
+        }
+
+        Ok(Self {
+            workchain_id: bytes[1] as i8 as i32,
+            hex: hex::encode(&bytes[2..34]),
+        })
+    }
+}
+
+/// Raw `workchain_id:hex` or user friendly base64url form
+impl FromStr for AccountAddress {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (workchain_id, hex) = match s.split_once(':') {
+            Some(raw) => raw,
+            // Hex without a workchain would otherwise be reported as broken base64
+            None if s.len() == 64 && s.bytes().all(|c| c.is_ascii_hexdigit()) => anyhow::bail!(
+                "Invalid address `{}`: raw addresses need their workchain, e.g. `0:{}`",
+                s,
+                s
+            ),
+            None => return Self::from_base64(s),
+        };
+
+        let workchain_id = i32::from_str(workchain_id)
+            .map_err(|e| anyhow::anyhow!("Invalid workchain id in `{}`: {}", s, e))?;
+        if hex.len() != 64 || !hex.bytes().all(|c| c.is_ascii_hexdigit()) {
+            anyhow::bail!("Invalid address `{}`: expected 64 hex digits", s);
+        }
+
+        Ok(Self {
+            workchain_id,
+            hex: hex.to_ascii_lowercase(),
+        })
+    }
+}
+
+impl fmt::Display for AccountAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.workchain_id, self.hex)
+    }
+}
+
+impl TryFrom<String> for AccountAddress {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        AccountAddress::from_str(&value)
+    }
+}
+
+impl From<AccountAddress> for String {
+    fn from(address: AccountAddress) -> Self {
+        address.to_string()
+    }
+}
+
+/// CRC-16/XMODEM the user friendly form is checksummed with
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0u16, |crc, &byte| {
+        (0..8).fold(crc ^ ((byte as u16) << 8), |crc, _| match crc & 0x8000 {
+            0 => crc << 1,
+            _ => (crc << 1) ^ 0x1021,
+        })
+    })
+}
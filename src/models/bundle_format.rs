@@ -0,0 +1,59 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+/// Encoding of rows in bundle files, bundles without it in the manifest are jsonl
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BundleFormat {
+    /// One JSON object per line
+    #[default]
+    Jsonl,
+    /// Spreadsheet friendly, nested values are JSON encoded and private keys excluded by default
+    Csv,
+    /// Columnar files for analytics with typed columns, compressed per page
+    Parquet,
+}
+
+impl BundleFormat {
+    /// Suffix of the file name, before the compression one
+    pub fn extension(self) -> &'static str {
+        match self {
+            BundleFormat::Jsonl => ".jsonl",
+            BundleFormat::Csv => ".csv",
+            BundleFormat::Parquet => ".parquet",
+        }
+    }
+
+    /// Whether bundles of this format can be imported and verified
+    pub fn is_importable(self) -> bool {
+        match self {
+            BundleFormat::Jsonl | BundleFormat::Parquet => true,
+            BundleFormat::Csv => false,
+        }
+    }
+}
+
+impl FromStr for BundleFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "jsonl" => Ok(BundleFormat::Jsonl),
+            "csv" => Ok(BundleFormat::Csv),
+            "parquet" => Ok(BundleFormat::Parquet),
+            _ => anyhow::bail!("Unknown format `{}`, expected jsonl, csv or parquet", s),
+        }
+    }
+}
+
+impl fmt::Display for BundleFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            BundleFormat::Jsonl => "jsonl",
+            BundleFormat::Csv => "csv",
+            BundleFormat::Parquet => "parquet",
+        })
+    }
+}
@@ -0,0 +1,170 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::utils::*;
+
+/// Tar block size, headers and entry data are aligned to it
+const BLOCK_SIZE: u64 = 512;
+
+/// Exported bundle, either a directory or a single tar archive
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BundleSource {
+    Dir(PathBuf),
+    Archive {
+        path: PathBuf,
+        compression: Compression,
+    },
+}
+
+impl BundleSource {
+    /// Directory at `path` or an archive recognized by its `.tar`, `.tar.gz`, `.tgz` or `.tar.zst` extension
+    pub fn new(path: PathBuf) -> Result<Self> {
+        if path.is_dir() {
+            return Ok(BundleSource::Dir(path));
+        }
+
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let compression = if name.ends_with(".tar") {
+            Compression::None
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Compression::Gzip
+        } else if name.ends_with(".tar.zst") {
+            Compression::Zstd
+        } else if !path.exists() {
+            anyhow::bail!("Bundle {} doesn't exist", path.display());
+        } else {
+            anyhow::bail!(
+                "{} is neither a directory nor a .tar, .tar.gz or .tar.zst archive",
+                path.display()
+            );
+        };
+
+        Ok(BundleSource::Archive { path, compression })
+    }
+
+    pub fn path(&self) -> &Path {
+        match self {
+            BundleSource::Dir(path) => path,
+            BundleSource::Archive { path, .. } => path,
+        }
+    }
+
+    pub fn open(&self, name: &str) -> Result<Box<dyn Read>> {
+        self.open_any(&[name.to_owned()])?
+            .map(|(_, reader)| reader)
+            .with_context(|| format!("Bundle has no {}", name))
+    }
+
+    /// Opens whichever of `names` the bundle has, returns its name and contents
+    pub fn open_any(&self, names: &[String]) -> Result<Option<(String, Box<dyn Read>)>> {
+        match self {
+            BundleSource::Dir(path) => {
+                let mut present = names.iter().filter(|name| path.join(name).exists());
+                match (present.next(), present.next()) {
+                    (Some(first), Some(second)) => {
+                        anyhow::bail!("Bundle has both {} and {}", first, second)
+                    }
+                    (Some(name), None) => {
+                        let file = File::open(path.join(name))
+                            .with_context(|| format!("Failed to open {}", name))?;
+                        Ok(Some((name.clone(), Box::new(file))))
+                    }
+                    (None, _) => Ok(None),
+                }
+            }
+            // Entries are read in a single pass without extracting anything
+            BundleSource::Archive { path, compression } => {
+                let file = File::open(path)
+                    .with_context(|| format!("Failed to open {}", path.display()))?;
+                let mut reader = compression.decoder(Box::new(file))?;
+
+                while let Some((name, size)) = next_entry(&mut reader)
+                    .with_context(|| format!("Invalid archive {}", path.display()))?
+                {
+                    if names.contains(&name) {
+                        return Ok(Some((name, Box::new(reader.take(size)))));
+                    }
+
+                    let padded = size.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+                    io::copy(&mut (&mut reader).take(padded), &mut io::sink())?;
+                }
+
+                Ok(None)
+            }
+        }
+    }
+
+    /// Calls `read` with the name and contents of every file of `names` the bundle has,
+    /// archives are read in a single pass in the order of their entries
+    pub fn read_each(
+        &self,
+        names: &[String],
+        mut read: impl FnMut(&str, &mut dyn Read) -> Result<()>,
+    ) -> Result<()> {
+        match self {
+            BundleSource::Dir(path) => {
+                for name in names.iter().filter(|name| path.join(name).exists()) {
+                    let mut file = File::open(path.join(name))
+                        .with_context(|| format!("Failed to open {}", name))?;
+                    read(name, &mut file)?;
+                }
+                Ok(())
+            }
+            BundleSource::Archive { path, compression } => {
+                let file = File::open(path)
+                    .with_context(|| format!("Failed to open {}", path.display()))?;
+                let mut reader = compression.decoder(Box::new(file))?;
+
+                while let Some((name, size)) = next_entry(&mut reader)
+                    .with_context(|| format!("Invalid archive {}", path.display()))?
+                {
+                    let mut skip = size.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+                    if names.contains(&name) {
+                        let mut entry = (&mut reader).take(size);
+                        read(&name, &mut entry)?;
+                        // Whatever `read` left of the entry is skipped with the padding
+                        skip -= size - entry.limit();
+                    }
+                    io::copy(&mut (&mut reader).take(skip), &mut io::sink())?;
+                }
+
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Reads the next tar header, returns the entry name and size or `None` at the end of the archive
+fn next_entry(reader: &mut dyn Read) -> io::Result<Option<(String, u64)>> {
+    loop {
+        let mut block = [0u8; BLOCK_SIZE as usize];
+        let mut read = 0;
+        while read < block.len() {
+            match reader.read(&mut block[read..])? {
+                0 if read == 0 => return Ok(None),
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                n => read += n,
+            }
+        }
+
+        // Archives end with zero blocks
+        if block.iter().all(|&byte| byte == 0) {
+            return Ok(None);
+        }
+
+        let header = tar::Header::from_byte_slice(&block);
+        let size = header.entry_size()?;
+        if header.entry_type().is_file() {
+            let path = header.path()?;
+            let name = path.strip_prefix("./").unwrap_or(&path);
+            return Ok(Some((name.to_string_lossy().into_owned(), size)));
+        }
+
+        // Directories, links and extended headers are skipped
+        let padded = size.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+        io::copy(&mut reader.take(padded), &mut io::sink())?;
+    }
+}
@@ -0,0 +1,89 @@
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::Result;
+
+use crate::models::*;
+
+/// Kind of rows a bundle file holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Entity {
+    Addresses,
+    Transactions,
+    TokenOwners,
+    TokenTransactions,
+}
+
+impl Entity {
+    /// Every entity in import order
+    pub const ALL: [Entity; 4] = [
+        Entity::Addresses,
+        Entity::Transactions,
+        Entity::TokenOwners,
+        Entity::TokenTransactions,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Entity::Addresses => "addresses",
+            Entity::Transactions => "transactions",
+            Entity::TokenOwners => "token_owners",
+            Entity::TokenTransactions => "token_transactions",
+        }
+    }
+
+    /// Bundle file the rows are stored in, e.g. `addresses.jsonl`
+    pub fn file(self, format: BundleFormat) -> String {
+        format!("{}{}", self.name(), format.extension())
+    }
+
+    /// Parses a comma separated list, e.g. `addresses,token_owners`
+    pub fn parse_list(s: &str) -> Result<Vec<Entity>> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(Entity::from_str)
+            .collect()
+    }
+
+    /// Entities in import order, restricted to `only` and without `skip`
+    pub fn select(only: Option<&[Entity]>, skip: Option<&[Entity]>) -> Result<Vec<Entity>> {
+        if only.is_some() && skip.is_some() {
+            anyhow::bail!("--only and --skip are mutually exclusive");
+        }
+
+        let entities = Entity::ALL
+            .into_iter()
+            .filter(|entity| only.is_none_or(|only| only.contains(entity)))
+            .filter(|entity| !skip.unwrap_or_default().contains(entity))
+            .collect::<Vec<_>>();
+        if entities.is_empty() {
+            anyhow::bail!("No entities selected");
+        }
+
+        Ok(entities)
+    }
+}
+
+impl FromStr for Entity {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "addresses" => Ok(Entity::Addresses),
+            "transactions" => Ok(Entity::Transactions),
+            "token_owners" => Ok(Entity::TokenOwners),
+            "token_transactions" => Ok(Entity::TokenTransactions),
+            _ => anyhow::bail!(
+                "Unknown entity `{}`, expected addresses, transactions, token_owners or token_transactions",
+                s
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Entity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
@@ -0,0 +1,43 @@
+use chrono::NaiveDateTime;
+
+use crate::models::*;
+
+/// Rows selected for export
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExportFilter {
+    /// Rows updated at or after this time (`created_at` for token owners)
+    pub since: Option<NaiveDateTime>,
+    /// Rows updated before this time (`created_at` for token owners)
+    pub until: Option<NaiveDateTime>,
+    /// Rows of these accounts only (owner accounts for token owners)
+    pub accounts: Option<Vec<AccountAddress>>,
+}
+
+impl ExportFilter {
+    /// Workchain ids and hex parts of `accounts` as separate arrays for `UNNEST`
+    pub fn account_columns(&self) -> (Option<Vec<i32>>, Option<Vec<String>>) {
+        match &self.accounts {
+            Some(accounts) => (
+                Some(
+                    accounts
+                        .iter()
+                        .map(|account| account.workchain_id)
+                        .collect(),
+                ),
+                Some(accounts.iter().map(|account| account.hex.clone()).collect()),
+            ),
+            None => (None, None),
+        }
+    }
+
+    /// Whether a row with the given timestamp and account passes the filter
+    pub fn matches(&self, timestamp: NaiveDateTime, workchain_id: i32, hex: &str) -> bool {
+        self.since.is_none_or(|since| timestamp >= since)
+            && self.until.is_none_or(|until| timestamp < until)
+            && self.accounts.as_ref().is_none_or(|accounts| {
+                accounts
+                    .iter()
+                    .any(|account| account.workchain_id == workchain_id && account.hex == hex)
+            })
+    }
+}
@@ -0,0 +1,97 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+/// Argon2 parameters the private keys encryption key is derived with
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub struct KdfParams {
+    pub variant: Argon2Variant,
+    /// Memory cost in KiB
+    pub memory_cost: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Argon2Variant {
+    Argon2d,
+    Argon2i,
+    Argon2id,
+}
+
+/// Argon2id v0x13 defaults every service was provisioned with before parameters became configurable
+impl Default for KdfParams {
+    fn default() -> Self {
+        Self {
+            variant: Argon2Variant::Argon2id,
+            memory_cost: argon2::Params::DEFAULT_M_COST,
+            iterations: argon2::Params::DEFAULT_T_COST,
+            parallelism: argon2::Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+/// Comma separated variant and `m`, `t`, `p` costs, e.g. `argon2i,m=65536,t=2,p=4`,
+/// omitted ones keep their defaults
+impl FromStr for KdfParams {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut params = KdfParams::default();
+
+        for item in s.split(',').map(str::trim).filter(|item| !item.is_empty()) {
+            let parse = |value: &str| {
+                u32::from_str(value)
+                    .map_err(|e| anyhow::anyhow!("Invalid KDF parameter `{}`: {}", item, e))
+            };
+
+            match item.split_once('=') {
+                Some(("m", value)) => params.memory_cost = parse(value)?,
+                Some(("t", value)) => params.iterations = parse(value)?,
+                Some(("p", value)) => params.parallelism = parse(value)?,
+                Some(_) => anyhow::bail!("Unknown KDF parameter `{}`, expected m, t or p", item),
+                None => params.variant = Argon2Variant::from_str(item)?,
+            }
+        }
+
+        Ok(params)
+    }
+}
+
+impl fmt::Display for KdfParams {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{},m={},t={},p={}",
+            self.variant, self.memory_cost, self.iterations, self.parallelism
+        )
+    }
+}
+
+impl FromStr for Argon2Variant {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "argon2d" => Ok(Argon2Variant::Argon2d),
+            "argon2i" => Ok(Argon2Variant::Argon2i),
+            "argon2id" => Ok(Argon2Variant::Argon2id),
+            _ => anyhow::bail!(
+                "Unknown Argon2 variant `{}`, expected argon2d, argon2i or argon2id",
+                s
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Argon2Variant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Argon2Variant::Argon2d => "argon2d",
+            Argon2Variant::Argon2i => "argon2i",
+            Argon2Variant::Argon2id => "argon2id",
+        })
+    }
+}
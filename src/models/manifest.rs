@@ -0,0 +1,270 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Read};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize};
+
+use crate::models::*;
+use crate::utils::*;
+
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// Version of the bundle layout, bumped on incompatible changes
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Bundle metadata written next to the exported files
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Manifest {
+    pub format_version: u32,
+    pub tool_version: String,
+    pub service_id: ServiceId,
+    pub exported_at: NaiveDateTime,
+    /// Encoding of the files, bundles without it are jsonl
+    #[serde(default)]
+    pub format: BundleFormat,
+    pub since: Option<NaiveDateTime>,
+    pub until: Option<NaiveDateTime>,
+    /// Start of the export or `until` if earlier, `since` of the next incremental export.
+    /// Rows are queried after it, so no row updated later can be missed by the next export
+    pub watermark: Option<NaiveDateTime>,
+    /// Accounts the export was restricted to, all of the service when absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub accounts: Option<Vec<AccountAddress>>,
+    /// Key derivation of the exporting service, bundles without it used the defaults
+    #[serde(default)]
+    pub kdf: KdfParams,
+    /// Key check value of the exporting service key
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_check: Option<String>,
+    /// Exported files, partial bundles lack the skipped entities
+    pub files: BTreeMap<String, ManifestFile>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ManifestFile {
+    pub rows: u64,
+    /// Hex encoded SHA-256 of the file contents before compression and encryption
+    pub sha256: String,
+    /// Set when the file is encrypted with a transport passphrase
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<TransportEncryption>,
+}
+
+impl ManifestFile {
+    /// Name of the file `name` is stored as in the bundle directory
+    pub fn stored_name(&self, name: &str, compression: Compression) -> String {
+        let suffix = match self.encryption {
+            Some(_) => ".enc",
+            None => "",
+        };
+        format!("{}{}{}", name, compression.extension(), suffix)
+    }
+}
+
+impl Manifest {
+    /// Manifest of an export of `service_id` starting at `exported_at`, without files yet
+    pub fn new(
+        service_id: ServiceId,
+        exported_at: NaiveDateTime,
+        filter: &ExportFilter,
+        kdf: KdfParams,
+        key_check: Option<String>,
+    ) -> Self {
+        Self {
+            format_version: FORMAT_VERSION,
+            tool_version: env!("CARGO_PKG_VERSION").to_owned(),
+            service_id,
+            exported_at,
+            format: BundleFormat::default(),
+            since: filter.since,
+            until: filter.until,
+            watermark: Some(
+                filter
+                    .until
+                    .map_or(exported_at, |until| until.min(exported_at)),
+            ),
+            accounts: filter.accounts.clone(),
+            kdf,
+            key_check,
+            files: BTreeMap::new(),
+        }
+    }
+
+    pub fn load(source: &BundleSource) -> Result<Self> {
+        let file = source
+            .open(MANIFEST_FILE)
+            .with_context(|| format!("Failed to open {}", source.path().display()))?;
+        serde_json::from_reader(file)
+            .with_context(|| format!("Invalid manifest in {}", source.path().display()))
+    }
+
+    pub fn store(&self, path: &Path) -> Result<()> {
+        let mut output = BufWriter::new(File::create(path.join(MANIFEST_FILE))?);
+        serde_json::to_writer_pretty(&mut output, self)?;
+        output
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+        Ok(())
+    }
+
+    /// Opens bundle file `name` for reading, decrypting it with `transport_passphrase`
+    /// and decompressing according to the file extension
+    pub fn open(
+        &self,
+        source: &BundleSource,
+        name: &str,
+        transport_passphrase: Option<&str>,
+    ) -> Result<Box<dyn Read>> {
+        let entry = self
+            .files
+            .get(name)
+            .with_context(|| format!("Manifest has no entry for {}", name))?;
+
+        let stored_names = Compression::ALL
+            .into_iter()
+            .map(|compression| entry.stored_name(name, compression))
+            .collect::<Vec<_>>();
+        let (stored_name, file) = source
+            .open_any(&stored_names)?
+            .with_context(|| format!("Bundle is incomplete, {} is missing", stored_names[0]))?;
+        let compression = Compression::ALL
+            .into_iter()
+            .find(|&compression| entry.stored_name(name, compression) == stored_name)
+            .unwrap_or_default();
+
+        decode(entry, &stored_name, compression, file, transport_passphrase)
+    }
+
+    /// File `name` when it is stored in a bundle directory neither compressed nor encrypted,
+    /// so it can seek, `None` otherwise or when it is missing
+    pub fn open_plain(&self, source: &BundleSource, name: &str) -> Result<Option<File>> {
+        let (BundleSource::Dir(dir), Some(entry)) = (source, self.files.get(name)) else {
+            return Ok(None);
+        };
+        if entry.encryption.is_some() {
+            return Ok(None);
+        }
+
+        // A compressed copy next to it is refused by `open`
+        let mut present = Compression::ALL
+            .into_iter()
+            .filter(|&compression| dir.join(entry.stored_name(name, compression)).exists());
+        match (present.next(), present.next()) {
+            (Some(Compression::None), None) => {
+                let file = File::open(dir.join(name))
+                    .with_context(|| format!("Failed to open {}", name))?;
+                Ok(Some(file))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Name of the bundle file of `entity` in the format of the bundle
+    pub fn file(&self, entity: Entity) -> String {
+        entity.file(self.format)
+    }
+
+    /// Entities exported into the bundle, in import order
+    pub fn entities(&self) -> Vec<Entity> {
+        Entity::ALL
+            .into_iter()
+            .filter(|&entity| self.files.contains_key(&self.file(entity)))
+            .collect()
+    }
+
+    /// Checks that files of `entities` present in the bundle match the recorded checksums
+    pub fn verify(
+        &self,
+        source: &BundleSource,
+        entities: &[Entity],
+        transport_passphrase: Option<&str>,
+    ) -> Result<()> {
+        if self.format_version != FORMAT_VERSION {
+            anyhow::bail!(
+                "Unsupported bundle format version {}, expected {}",
+                self.format_version,
+                FORMAT_VERSION
+            );
+        }
+
+        // Files of other entities are neither read nor required
+        let mut stored_names = BTreeMap::new();
+        for &entity in entities {
+            let name = self.file(entity);
+            if let Some(entry) = self.files.get(&name) {
+                for compression in Compression::ALL {
+                    stored_names.insert(
+                        entry.stored_name(&name, compression),
+                        (name.clone(), entry, compression),
+                    );
+                }
+            }
+        }
+
+        // Archives are read once, in the order of their entries
+        let names = stored_names.keys().cloned().collect::<Vec<_>>();
+        let mut verified = BTreeMap::new();
+        source.read_each(&names, |stored_name, file| {
+            let (name, expected, compression) = &stored_names[stored_name];
+            if let Some(other) = verified.insert(name.clone(), stored_name.to_owned()) {
+                anyhow::bail!("Bundle has both {} and {}", other, stored_name);
+            }
+
+            let file = decode(
+                expected,
+                stored_name,
+                *compression,
+                Box::new(file),
+                transport_passphrase,
+            )?;
+            let (sha256, rows) =
+                hash_lines(file).with_context(|| format!("Failed to read {}", name))?;
+
+            // Only jsonl has a line per row, the checksum covers the others
+            if self.format == BundleFormat::Jsonl && rows != expected.rows {
+                anyhow::bail!(
+                    "{} has {} rows, manifest expects {}",
+                    name,
+                    rows,
+                    expected.rows
+                );
+            }
+            if sha256 != expected.sha256 {
+                anyhow::bail!("{} checksum mismatch", name);
+            }
+            Ok(())
+        })?;
+
+        for (stored_name, (name, _, compression)) in &stored_names {
+            if *compression == Compression::None && !verified.contains_key(name) {
+                anyhow::bail!("Bundle is incomplete, {} is missing", stored_name);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Decrypts bundle file `stored_name` with `transport_passphrase` and decompresses it
+fn decode<'a>(
+    entry: &ManifestFile,
+    stored_name: &str,
+    compression: Compression,
+    file: Box<dyn Read + 'a>,
+    transport_passphrase: Option<&str>,
+) -> Result<Box<dyn Read + 'a>> {
+    let reader: Box<dyn Read + 'a> = match (&entry.encryption, transport_passphrase) {
+        (None, _) => file,
+        (Some(encryption), Some(passphrase)) => Box::new(encryption.decryptor(passphrase, file)?),
+        (Some(_), None) => anyhow::bail!(
+            "{} is encrypted, transport passphrase is required",
+            stored_name
+        ),
+    };
+
+    Ok(compression.decoder(reader)?)
+}
@@ -1,7 +1,21 @@
+pub use self::account_address::*;
 pub use self::account_enums::*;
+pub use self::bundle_format::*;
+pub use self::bundle_source::*;
+pub use self::entity::*;
+pub use self::export_filter::*;
+pub use self::kdf_params::*;
+pub use self::manifest::*;
 pub use self::service_id::*;
 pub use self::sqlx::*;
 
+mod account_address;
 mod account_enums;
+mod bundle_format;
+mod bundle_source;
+mod entity;
+mod export_filter;
+mod kdf_params;
+mod manifest;
 mod service_id;
 mod sqlx;
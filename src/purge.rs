@@ -0,0 +1,150 @@
+use std::fmt;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use zeroize::Zeroizing;
+
+use crate::models::*;
+use crate::sqlx_client::*;
+use crate::utils::*;
+use crate::verify::*;
+
+/// Deletes all rows of the service in a single transaction once the bundle at `path`
+/// is verified to hold every one of them, nothing is committed on `dry_run`
+pub async fn run_purge(
+    service_id: ServiceId,
+    path: PathBuf,
+    key: Zeroizing<[u8; 32]>,
+    transport_passphrase: Option<&str>,
+    dry_run: bool,
+) -> Result<PurgeReport> {
+    let source = BundleSource::new(path.clone())?;
+    let manifest = Manifest::load(&source)?;
+    check_full_export(&manifest, service_id)?;
+
+    // Checksums, then every row against the database
+    let verified = run_verify(None, path, key, transport_passphrase)
+        .await
+        .context("Bundle verification failed")?;
+    if !verified.is_consistent() {
+        anyhow::bail!(
+            "Bundle and database diverge, refusing to purge:\n{}",
+            verified
+        );
+    }
+
+    let pool = get_pg_pool().await?;
+    let sqlx_client = SqlxClient::new(pool);
+
+    let mut tx = sqlx_client.begin().await?;
+
+    let mut report = PurgeReport {
+        dry_run,
+        entities: Vec::with_capacity(Entity::ALL.len()),
+    };
+    // Token owners are found through addresses, so they go first
+    for entity in [
+        Entity::TokenOwners,
+        Entity::TokenTransactions,
+        Entity::Transactions,
+        Entity::Addresses,
+    ] {
+        let deleted = match entity {
+            Entity::TokenOwners => sqlx_client.delete_token_owners(&mut tx, service_id).await?,
+            Entity::TokenTransactions => {
+                sqlx_client
+                    .delete_token_transactions(&mut tx, service_id)
+                    .await?
+            }
+            Entity::Transactions => sqlx_client.delete_transactions(&mut tx, service_id).await?,
+            Entity::Addresses => sqlx_client.delete_addresses(&mut tx, service_id).await?,
+        };
+
+        // Rows written after verification are not in the bundle and rows updated after the export
+        // differ from it, in both cases the transaction is rolled back
+        let exported = manifest.files[&manifest.file(entity)].rows;
+        if deleted.rows != exported {
+            anyhow::bail!(
+                "Service has {} {} rows, bundle has {}, it changed since verification",
+                deleted.rows,
+                entity,
+                exported
+            );
+        }
+        let watermark = manifest.watermark.unwrap_or(manifest.exported_at);
+        if let Some(last_updated_at) = deleted.last_updated_at.filter(|&at| at > watermark) {
+            anyhow::bail!(
+                "{} rows were updated at {}, after the export at {}",
+                entity,
+                last_updated_at,
+                watermark
+            );
+        }
+
+        report.entities.push((entity, deleted));
+    }
+
+    if !dry_run {
+        tx.commit().await?;
+    }
+
+    Ok(report)
+}
+
+/// Fails unless the manifest describes an export of all rows of the service
+fn check_full_export(manifest: &Manifest, service_id: ServiceId) -> Result<()> {
+    if manifest.service_id != service_id {
+        anyhow::bail!(
+            "Bundle is an export of service {}, not {}",
+            manifest.service_id,
+            service_id
+        );
+    }
+
+    let partial = if !manifest.format.is_importable() {
+        Some("it is in a format which can't be imported")
+    } else if manifest.format == BundleFormat::Parquet {
+        Some("parquet bundles have no private keys")
+    } else if manifest.since.is_some() || manifest.until.is_some() {
+        Some("it is restricted by time")
+    } else if manifest.accounts.is_some() {
+        Some("it is restricted to some accounts")
+    } else if manifest.entities() != Entity::ALL {
+        Some("some entities were skipped")
+    } else {
+        None
+    };
+    if let Some(reason) = partial {
+        anyhow::bail!("Purge needs a full export, but {}", reason);
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PurgeReport {
+    pub dry_run: bool,
+    /// Deleted rows of every entity
+    pub entities: Vec<(Entity, DeletedRows)>,
+}
+
+impl fmt::Display for PurgeReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = match self.dry_run {
+            true => "would be deleted",
+            false => "deleted",
+        };
+        for (entity, deleted) in &self.entities {
+            write!(f, "{}: {} rows {}", entity, deleted.deleted, action)?;
+            if deleted.rows > deleted.deleted {
+                write!(
+                    f,
+                    ", {} shared with other services kept",
+                    deleted.rows - deleted.deleted
+                )?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,92 @@
+use anyhow::{Context, Result};
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+use crate::models::*;
+use crate::sqlx_client::*;
+use crate::utils::*;
+
+/// Addresses re-encrypted with a single query
+const REKEY_BATCH_SIZE: i64 = 1_000;
+
+/// Re-encrypts private keys of all service addresses from `old_key` to `new_key`
+/// in a single transaction, returns the number of updated addresses
+pub async fn run_rekey(
+    service_id: ServiceId,
+    old_key: Zeroizing<[u8; 32]>,
+    new_key: Zeroizing<[u8; 32]>,
+) -> Result<u64> {
+    if old_key == new_key {
+        anyhow::bail!("New key is the same as the old one");
+    }
+
+    let pool = get_pg_pool().await?;
+    let sqlx_client = SqlxClient::new(pool);
+
+    let mut tx = sqlx_client.begin().await?;
+
+    let mut updated = 0;
+    let mut after = Uuid::nil();
+    loop {
+        let addresses = sqlx_client
+            .get_addresses_page_for_update(&mut tx, service_id, after, REKEY_BATCH_SIZE)
+            .await?;
+        after = match addresses.last() {
+            Some(address) => address.id,
+            None => break,
+        };
+
+        let mut ids = Vec::with_capacity(addresses.len());
+        let mut private_keys = Vec::with_capacity(addresses.len());
+        for address in addresses {
+            let private_key =
+                decrypt(&address.private_key, &old_key, &address.id).with_context(|| {
+                    format!("Failed to decrypt address {} with the old key", address.id)
+                })?;
+
+            let encoded = Zeroizing::new(base64::encode(&*private_key));
+            let encrypted = encrypt(&encoded, &new_key, &address.id)?;
+            if decrypt(&encrypted, &new_key, &address.id)? != private_key {
+                anyhow::bail!(
+                    "Re-encrypted key of address {} does not round-trip",
+                    address.id
+                );
+            }
+
+            ids.push(address.id);
+            private_keys.push(encrypted);
+        }
+
+        updated += sqlx_client
+            .update_private_keys(&mut tx, &ids, &private_keys)
+            .await?;
+    }
+
+    // Read the stored keys back, the transaction is rolled back on any failure
+    let mut checked = 0;
+    let mut after = Uuid::nil();
+    loop {
+        let addresses = sqlx_client
+            .get_addresses_page_for_update(&mut tx, service_id, after, REKEY_BATCH_SIZE)
+            .await?;
+        after = match addresses.last() {
+            Some(address) => address.id,
+            None => break,
+        };
+
+        for address in addresses {
+            decrypt(&address.private_key, &new_key, &address.id).with_context(|| {
+                format!("Stored key of address {} does not decrypt", address.id)
+            })?;
+            checked += 1;
+        }
+    }
+
+    if checked != updated {
+        anyhow::bail!("Updated {} addresses, but {} are stored", updated, checked);
+    }
+
+    tx.commit().await?;
+
+    Ok(updated)
+}
@@ -0,0 +1,349 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::sync::{Mutex, MutexGuard};
+
+use async_trait::async_trait;
+use chrono::{NaiveDateTime, Utc};
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use uuid::Uuid;
+
+use crate::error::*;
+use crate::repository::*;
+use crate::sqlx_client::CopyRow;
+
+/// Repository keeping rows in memory, for running migrations without PostgreSQL.
+///
+/// Only primary keys are enforced, conflicts are handled the same way as by `COPY` imports.
+#[derive(Debug, Default)]
+pub struct MemoryRepository {
+    state: Mutex<MemoryState>,
+}
+
+/// Rows of every table by primary key
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryState {
+    pub addresses: BTreeMap<Uuid, AddressDb>,
+    pub transactions: BTreeMap<Uuid, TransactionDb>,
+    pub token_owners: BTreeMap<String, TokenOwnerDb>,
+    pub token_transactions: BTreeMap<Uuid, TokenTransactionDb>,
+}
+
+/// Rows written in a transaction, visible to readers once committed
+#[derive(Debug, Default)]
+pub struct MemoryTransaction {
+    pending: MemoryState,
+}
+
+impl MemoryRepository {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_state(state: MemoryState) -> Self {
+        Self {
+            state: Mutex::new(state),
+        }
+    }
+
+    /// Copy of the committed rows
+    pub fn snapshot(&self) -> MemoryState {
+        self.state().clone()
+    }
+
+    fn state(&self) -> MutexGuard<'_, MemoryState> {
+        // Rows are only replaced as a whole, so a panicked writer leaves them consistent
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn stream<'a, R, F>(&'a self, rows: F) -> BoxStream<'a, Result<R>>
+    where
+        R: Send + 'a,
+        F: FnOnce(&MemoryState) -> Vec<R>,
+    {
+        let rows = rows(&self.state());
+        futures::stream::iter(rows.into_iter().map(Ok)).boxed()
+    }
+
+    fn insert<R: MemoryRow>(
+        &self,
+        tx: &mut MemoryTransaction,
+        rows: &[R],
+        on_conflict: OnConflict,
+    ) -> Result<u64> {
+        let state = self.state();
+
+        let mut written = 0;
+        for row in rows {
+            let key = row.key();
+            let existing = R::table(&tx.pending)
+                .get(&key)
+                .or_else(|| R::table(&state).get(&key));
+
+            let row = match (existing, on_conflict) {
+                (None, _) => row.clone(),
+                (Some(_), OnConflict::Fail) => {
+                    return Err(Error::DuplicateRow {
+                        entity: R::ENTITY,
+                        key: R::KEY.to_owned(),
+                        id: key.to_string(),
+                        file: None,
+                        line: None,
+                    })
+                }
+                (Some(existing), OnConflict::Update) => match existing.updated(row) {
+                    Some(updated) => updated,
+                    None => continue,
+                },
+                (Some(_), OnConflict::Skip) => continue,
+            };
+
+            R::table_mut(&mut tx.pending).insert(key, row);
+            written += 1;
+        }
+
+        Ok(written)
+    }
+}
+
+#[async_trait]
+impl Repository for MemoryRepository {
+    type Transaction = MemoryTransaction;
+
+    fn stream_all_addresses(
+        &self,
+        service_id: ServiceId,
+        filter: &ExportFilter,
+    ) -> BoxStream<'_, Result<AddressDb>> {
+        self.stream(|state| {
+            state
+                .addresses
+                .values()
+                .filter(|address| address.service_id == service_id)
+                .filter(|address| {
+                    filter.matches(address.updated_at, address.workchain_id, &address.hex)
+                })
+                .cloned()
+                .collect()
+        })
+    }
+
+    fn stream_all_transactions(
+        &self,
+        service_id: ServiceId,
+        filter: &ExportFilter,
+    ) -> BoxStream<'_, Result<TransactionDb>> {
+        self.stream(|state| {
+            state
+                .transactions
+                .values()
+                .filter(|transaction| transaction.service_id == service_id)
+                .filter(|transaction| {
+                    filter.matches(
+                        transaction.updated_at,
+                        transaction.account_workchain_id,
+                        &transaction.account_hex,
+                    )
+                })
+                .cloned()
+                .collect()
+        })
+    }
+
+    fn stream_all_token_owners(
+        &self,
+        service_id: ServiceId,
+        filter: &ExportFilter,
+    ) -> BoxStream<'_, Result<TokenOwnerDb>> {
+        self.stream(|state| {
+            let accounts = state
+                .addresses
+                .values()
+                .filter(|address| address.service_id == service_id)
+                .map(|address| (address.workchain_id, address.hex.as_str()))
+                .collect::<HashSet<_>>();
+
+            state
+                .token_owners
+                .values()
+                .filter(|owner| {
+                    accounts.contains(&(
+                        owner.owner_account_workchain_id,
+                        owner.owner_account_hex.as_str(),
+                    ))
+                })
+                .filter(|owner| {
+                    filter.matches(
+                        owner.created_at,
+                        owner.owner_account_workchain_id,
+                        &owner.owner_account_hex,
+                    )
+                })
+                .cloned()
+                .collect()
+        })
+    }
+
+    fn stream_all_token_transactions(
+        &self,
+        service_id: ServiceId,
+        filter: &ExportFilter,
+    ) -> BoxStream<'_, Result<TokenTransactionDb>> {
+        self.stream(|state| {
+            state
+                .token_transactions
+                .values()
+                .filter(|transaction| transaction.service_id == service_id)
+                .filter(|transaction| {
+                    filter.matches(
+                        transaction.updated_at,
+                        transaction.account_workchain_id,
+                        &transaction.account_hex,
+                    )
+                })
+                .cloned()
+                .collect()
+        })
+    }
+
+    async fn get_any_address(&self, service_id: ServiceId) -> Result<Option<AddressDb>> {
+        Ok(self
+            .state()
+            .addresses
+            .values()
+            .find(|address| address.service_id == service_id)
+            .cloned())
+    }
+
+    async fn now(&self) -> Result<NaiveDateTime> {
+        Ok(Utc::now().naive_utc())
+    }
+
+    async fn begin(&self) -> Result<Self::Transaction> {
+        Ok(MemoryTransaction::default())
+    }
+
+    async fn commit(&self, tx: Self::Transaction) -> Result<()> {
+        let mut state = self.state();
+        let pending = tx.pending;
+
+        state.addresses.extend(pending.addresses);
+        state.transactions.extend(pending.transactions);
+        state.token_owners.extend(pending.token_owners);
+        state.token_transactions.extend(pending.token_transactions);
+
+        Ok(())
+    }
+
+    async fn insert_addresses(
+        &self,
+        tx: &mut Self::Transaction,
+        rows: &[AddressDb],
+        on_conflict: OnConflict,
+    ) -> Result<u64> {
+        self.insert(tx, rows, on_conflict)
+    }
+
+    async fn insert_transactions(
+        &self,
+        tx: &mut Self::Transaction,
+        rows: &[TransactionDb],
+        on_conflict: OnConflict,
+    ) -> Result<u64> {
+        self.insert(tx, rows, on_conflict)
+    }
+
+    async fn insert_token_owners(
+        &self,
+        tx: &mut Self::Transaction,
+        rows: &[TokenOwnerDb],
+        on_conflict: OnConflict,
+    ) -> Result<u64> {
+        self.insert(tx, rows, on_conflict)
+    }
+
+    async fn insert_token_transactions(
+        &self,
+        tx: &mut Self::Transaction,
+        rows: &[TokenTransactionDb],
+        on_conflict: OnConflict,
+    ) -> Result<u64> {
+        self.insert(tx, rows, on_conflict)
+    }
+}
+
+/// Row stored in one of the [`MemoryState`] tables, [`OnConflict::Update`] refreshes
+/// the same columns as in PostgreSQL through [`CopyRow::updated`]
+trait MemoryRow: CopyRow + Clone {
+    type Key: Ord + fmt::Display;
+
+    fn key(&self) -> Self::Key;
+
+    fn table(state: &MemoryState) -> &BTreeMap<Self::Key, Self>;
+
+    fn table_mut(state: &mut MemoryState) -> &mut BTreeMap<Self::Key, Self>;
+}
+
+impl MemoryRow for AddressDb {
+    type Key = Uuid;
+
+    fn key(&self) -> Uuid {
+        self.id
+    }
+
+    fn table(state: &MemoryState) -> &BTreeMap<Uuid, Self> {
+        &state.addresses
+    }
+
+    fn table_mut(state: &mut MemoryState) -> &mut BTreeMap<Uuid, Self> {
+        &mut state.addresses
+    }
+}
+
+impl MemoryRow for TransactionDb {
+    type Key = Uuid;
+
+    fn key(&self) -> Uuid {
+        self.id
+    }
+
+    fn table(state: &MemoryState) -> &BTreeMap<Uuid, Self> {
+        &state.transactions
+    }
+
+    fn table_mut(state: &mut MemoryState) -> &mut BTreeMap<Uuid, Self> {
+        &mut state.transactions
+    }
+}
+
+impl MemoryRow for TokenOwnerDb {
+    type Key = String;
+
+    fn key(&self) -> String {
+        self.address.clone()
+    }
+
+    fn table(state: &MemoryState) -> &BTreeMap<String, Self> {
+        &state.token_owners
+    }
+
+    fn table_mut(state: &mut MemoryState) -> &mut BTreeMap<String, Self> {
+        &mut state.token_owners
+    }
+}
+
+impl MemoryRow for TokenTransactionDb {
+    type Key = Uuid;
+
+    fn key(&self) -> Uuid {
+        self.id
+    }
+
+    fn table(state: &MemoryState) -> &BTreeMap<Uuid, Self> {
+        &state.token_transactions
+    }
+
+    fn table_mut(state: &mut MemoryState) -> &mut BTreeMap<Uuid, Self> {
+        &mut state.token_transactions
+    }
+}
@@ -0,0 +1,122 @@
+use async_trait::async_trait;
+use chrono::NaiveDateTime;
+use futures::stream::BoxStream;
+
+use crate::error::Result;
+use crate::models::*;
+use crate::sqlx_client::OnConflict;
+
+pub use self::memory::*;
+
+mod memory;
+mod postgres;
+
+/// Storage rows are exported from and imported into
+#[async_trait]
+pub trait Repository: Send + Sync {
+    /// Writes committed together, dropping it without [`Repository::commit`] discards them
+    type Transaction: Send;
+
+    fn stream_all_addresses(
+        &self,
+        service_id: ServiceId,
+        filter: &ExportFilter,
+    ) -> BoxStream<'_, Result<AddressDb>>;
+
+    fn stream_all_transactions(
+        &self,
+        service_id: ServiceId,
+        filter: &ExportFilter,
+    ) -> BoxStream<'_, Result<TransactionDb>>;
+
+    /// Token owners of the service addresses
+    fn stream_all_token_owners(
+        &self,
+        service_id: ServiceId,
+        filter: &ExportFilter,
+    ) -> BoxStream<'_, Result<TokenOwnerDb>>;
+
+    fn stream_all_token_transactions(
+        &self,
+        service_id: ServiceId,
+        filter: &ExportFilter,
+    ) -> BoxStream<'_, Result<TokenTransactionDb>>;
+
+    /// Some address of the service, used to check the key before touching anything
+    async fn get_any_address(&self, service_id: ServiceId) -> Result<Option<AddressDb>>;
+
+    /// Current time of the storage, rows updated later are left to the next incremental export
+    async fn now(&self) -> Result<NaiveDateTime>;
+
+    async fn begin(&self) -> Result<Self::Transaction>;
+
+    async fn commit(&self, tx: Self::Transaction) -> Result<()>;
+
+    /// Writes `rows`, returns the number of inserted or updated ones
+    async fn insert_addresses(
+        &self,
+        tx: &mut Self::Transaction,
+        rows: &[AddressDb],
+        on_conflict: OnConflict,
+    ) -> Result<u64>;
+
+    async fn insert_transactions(
+        &self,
+        tx: &mut Self::Transaction,
+        rows: &[TransactionDb],
+        on_conflict: OnConflict,
+    ) -> Result<u64>;
+
+    async fn insert_token_owners(
+        &self,
+        tx: &mut Self::Transaction,
+        rows: &[TokenOwnerDb],
+        on_conflict: OnConflict,
+    ) -> Result<u64>;
+
+    async fn insert_token_transactions(
+        &self,
+        tx: &mut Self::Transaction,
+        rows: &[TokenTransactionDb],
+        on_conflict: OnConflict,
+    ) -> Result<u64>;
+}
+
+/// Row written with the matching `insert_*` method of a [`Repository`]
+#[async_trait]
+pub trait RepositoryRow: Sized + Send + Sync {
+    async fn insert<S>(
+        repository: &S,
+        tx: &mut S::Transaction,
+        rows: &[Self],
+        on_conflict: OnConflict,
+    ) -> Result<u64>
+    where
+        S: Repository;
+}
+
+macro_rules! impl_repository_row {
+    ($($row:ty => $insert:ident),*) => {
+        $(#[async_trait]
+        impl RepositoryRow for $row {
+            async fn insert<S>(
+                repository: &S,
+                tx: &mut S::Transaction,
+                rows: &[Self],
+                on_conflict: OnConflict,
+            ) -> Result<u64>
+            where
+                S: Repository,
+            {
+                repository.$insert(tx, rows, on_conflict).await
+            }
+        })*
+    };
+}
+
+impl_repository_row!(
+    AddressDb => insert_addresses,
+    TransactionDb => insert_transactions,
+    TokenOwnerDb => insert_token_owners,
+    TokenTransactionDb => insert_token_transactions
+);
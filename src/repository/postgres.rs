@@ -0,0 +1,98 @@
+use async_trait::async_trait;
+use chrono::NaiveDateTime;
+use futures::stream::BoxStream;
+use sqlx::{Postgres, Transaction};
+
+use crate::error::Result;
+use crate::repository::*;
+use crate::sqlx_client::*;
+
+/// Reads with the export queries and writes with `COPY`
+#[async_trait]
+impl Repository for SqlxClient {
+    type Transaction = Transaction<'static, Postgres>;
+
+    fn stream_all_addresses(
+        &self,
+        service_id: ServiceId,
+        filter: &ExportFilter,
+    ) -> BoxStream<'_, Result<AddressDb>> {
+        SqlxClient::stream_all_addresses(self, service_id, filter)
+    }
+
+    fn stream_all_transactions(
+        &self,
+        service_id: ServiceId,
+        filter: &ExportFilter,
+    ) -> BoxStream<'_, Result<TransactionDb>> {
+        SqlxClient::stream_all_transactions(self, service_id, filter)
+    }
+
+    fn stream_all_token_owners(
+        &self,
+        service_id: ServiceId,
+        filter: &ExportFilter,
+    ) -> BoxStream<'_, Result<TokenOwnerDb>> {
+        SqlxClient::stream_all_token_owners(self, service_id, filter)
+    }
+
+    fn stream_all_token_transactions(
+        &self,
+        service_id: ServiceId,
+        filter: &ExportFilter,
+    ) -> BoxStream<'_, Result<TokenTransactionDb>> {
+        SqlxClient::stream_all_token_transactions(self, service_id, filter)
+    }
+
+    async fn get_any_address(&self, service_id: ServiceId) -> Result<Option<AddressDb>> {
+        SqlxClient::get_any_address(self, service_id).await
+    }
+
+    async fn now(&self) -> Result<NaiveDateTime> {
+        SqlxClient::now(self).await
+    }
+
+    async fn begin(&self) -> Result<Self::Transaction> {
+        SqlxClient::begin(self).await
+    }
+
+    async fn commit(&self, tx: Self::Transaction) -> Result<()> {
+        tx.commit().await.map_err(From::from)
+    }
+
+    async fn insert_addresses(
+        &self,
+        tx: &mut Self::Transaction,
+        rows: &[AddressDb],
+        on_conflict: OnConflict,
+    ) -> Result<u64> {
+        copy_rows(tx, rows, on_conflict).await
+    }
+
+    async fn insert_transactions(
+        &self,
+        tx: &mut Self::Transaction,
+        rows: &[TransactionDb],
+        on_conflict: OnConflict,
+    ) -> Result<u64> {
+        copy_rows(tx, rows, on_conflict).await
+    }
+
+    async fn insert_token_owners(
+        &self,
+        tx: &mut Self::Transaction,
+        rows: &[TokenOwnerDb],
+        on_conflict: OnConflict,
+    ) -> Result<u64> {
+        copy_rows(tx, rows, on_conflict).await
+    }
+
+    async fn insert_token_transactions(
+        &self,
+        tx: &mut Self::Transaction,
+        rows: &[TokenTransactionDb],
+        on_conflict: OnConflict,
+    ) -> Result<u64> {
+        copy_rows(tx, rows, on_conflict).await
+    }
+}
@@ -1,5 +1,9 @@
-use anyhow::Result;
+use futures::stream::BoxStream;
+use futures::{StreamExt, TryStreamExt};
+use sqlx::PgConnection;
+use uuid::Uuid;
 
+use crate::error::Result;
 use crate::models::*;
 use crate::sqlx_client::*;
 
@@ -80,4 +84,175 @@ impl SqlxClient {
             .await
             .map_err(From::from)
     }
+
+    pub async fn get_any_address(&self, service_id: ServiceId) -> Result<Option<AddressDb>> {
+        sqlx::query_as!(AddressDb,
+                r#"SELECT id, service_id as "service_id: _", workchain_id, hex, base64url, public_key, private_key, account_type as "account_type: _",
+                custodians, confirmations, custodians_public_keys, balance, created_at, updated_at
+                FROM address WHERE service_id = $1 LIMIT 1"#,
+                service_id as ServiceId,
+            )
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(From::from)
+    }
+
+    pub fn stream_all_addresses(
+        &self,
+        service_id: ServiceId,
+        filter: &ExportFilter,
+    ) -> BoxStream<'_, Result<AddressDb>> {
+        let (workchain_ids, hexes) = filter.account_columns();
+        sqlx::query_as!(AddressDb,
+                r#"SELECT id, service_id as "service_id: _", workchain_id, hex, base64url, public_key, private_key, account_type as "account_type: _",
+                custodians, confirmations, custodians_public_keys, balance, created_at, updated_at
+                FROM address
+                WHERE service_id = $1 AND ($2::timestamp IS NULL OR updated_at >= $2) AND ($3::timestamp IS NULL OR updated_at < $3) AND ($4::int[] IS NULL OR (workchain_id, hex) IN (SELECT * FROM UNNEST($4::int[], $5::varchar[])))
+                ORDER BY id"#,
+                service_id as ServiceId,
+                filter.since,
+                filter.until,
+                workchain_ids.as_deref(),
+                hexes.as_deref(),
+            )
+            .fetch(&self.pool)
+            .map_err(From::from)
+            .boxed()
+    }
+
+    /// Ids of the addresses of the service passing `filter` after `after`, at most `limit` in id order
+    pub async fn get_address_ids_after(
+        &self,
+        service_id: ServiceId,
+        filter: &ExportFilter,
+        after: Option<Uuid>,
+        limit: i64,
+    ) -> Result<Vec<Uuid>> {
+        let (workchain_ids, hexes) = filter.account_columns();
+        sqlx::query_scalar!(
+            r#"SELECT id FROM address
+            WHERE service_id = $1 AND ($2::timestamp IS NULL OR updated_at >= $2) AND ($3::timestamp IS NULL OR updated_at < $3) AND ($4::int[] IS NULL OR (workchain_id, hex) IN (SELECT * FROM UNNEST($4::int[], $5::varchar[])))
+            AND ($6::uuid IS NULL OR id > $6)
+            ORDER BY id LIMIT $7"#,
+            service_id as ServiceId,
+            filter.since,
+            filter.until,
+            workchain_ids.as_deref(),
+            hexes.as_deref(),
+            after,
+            limit,
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(From::from)
+    }
+
+    pub async fn get_addresses_by_ids(&self, ids: &[Uuid]) -> Result<Vec<AddressDb>> {
+        sqlx::query_as!(AddressDb,
+                r#"SELECT id, service_id as "service_id: _", workchain_id, hex, base64url, public_key, private_key, account_type as "account_type: _",
+                custodians, confirmations, custodians_public_keys, balance, created_at, updated_at
+                FROM address WHERE id = ANY($1)"#,
+                ids,
+            )
+            .fetch_all(&self.pool)
+            .await
+            .map_err(From::from)
+    }
+
+    /// Locks and returns up to `limit` addresses of the service with ids greater than `after`
+    pub async fn get_addresses_page_for_update(
+        &self,
+        conn: &mut PgConnection,
+        service_id: ServiceId,
+        after: Uuid,
+        limit: i64,
+    ) -> Result<Vec<AddressDb>> {
+        sqlx::query_as!(AddressDb,
+                r#"SELECT id, service_id as "service_id: _", workchain_id, hex, base64url, public_key, private_key, account_type as "account_type: _",
+                custodians, confirmations, custodians_public_keys, balance, created_at, updated_at
+                FROM address WHERE service_id = $1 AND id > $2
+                ORDER BY id LIMIT $3
+                FOR UPDATE"#,
+                service_id as ServiceId,
+                after,
+                limit,
+            )
+            .fetch_all(&mut *conn)
+            .await
+            .map_err(From::from)
+    }
+
+    pub async fn update_private_keys(
+        &self,
+        conn: &mut PgConnection,
+        ids: &[Uuid],
+        private_keys: &[String],
+    ) -> Result<u64> {
+        let res = sqlx::query!(
+            r#"UPDATE address SET private_key = u.private_key
+            FROM UNNEST($1::uuid[], $2::varchar[]) AS u(id, private_key)
+            WHERE address.id = u.id"#,
+            ids,
+            private_keys,
+        )
+        .execute(&mut *conn)
+        .await?;
+
+        Ok(res.rows_affected())
+    }
+
+    /// Deletes all addresses of the service
+    pub async fn delete_addresses(
+        &self,
+        conn: &mut PgConnection,
+        service_id: ServiceId,
+    ) -> Result<DeletedRows> {
+        let res = sqlx::query!(
+            r#"WITH deleted AS (DELETE FROM address WHERE service_id = $1 RETURNING updated_at)
+            SELECT count(*) AS "rows!", max(updated_at) AS last_updated_at FROM deleted"#,
+            service_id as ServiceId,
+        )
+        .fetch_one(&mut *conn)
+        .await?;
+
+        Ok(DeletedRows {
+            rows: res.rows as u64,
+            deleted: res.rows as u64,
+            last_updated_at: res.last_updated_at,
+        })
+    }
+}
+
+impl CopyRow for AddressDb {
+    const ENTITY: Entity = Entity::Addresses;
+    const TABLE: &'static str = "address";
+    const COLUMNS: &'static str =
+        "id, service_id, workchain_id, hex, base64url, public_key, private_key, account_type, \
+        custodians, confirmations, custodians_public_keys, balance, created_at, updated_at";
+    const UNIQUE_KEYS: &'static [&'static str] = &["workchain_id, hex"];
+    // Exported balances are zeroed, so the target keeps its own
+    mutable_columns!(
+        custodians,
+        confirmations,
+        custodians_public_keys,
+        updated_at
+    );
+
+    fn write_record(&self, record: &mut CopyRecord<'_>) {
+        record
+            .push(&self.id)
+            .push(&self.service_id)
+            .push(&self.workchain_id)
+            .push(&self.hex)
+            .push(&self.base64url)
+            .push(&self.public_key)
+            .push(&self.private_key)
+            .push(&self.account_type)
+            .push(&self.custodians)
+            .push(&self.confirmations)
+            .push(&self.custodians_public_keys)
+            .push(&self.balance)
+            .push(&self.created_at)
+            .push(&self.updated_at);
+    }
 }
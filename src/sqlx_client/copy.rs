@@ -0,0 +1,352 @@
+use std::fmt::Write;
+use std::str::FromStr;
+
+use bigdecimal::BigDecimal;
+use chrono::NaiveDateTime;
+use sqlx::postgres::PgDatabaseError;
+use sqlx::PgConnection;
+use uuid::Uuid;
+
+use crate::error::*;
+use crate::models::*;
+
+/// Row that can be bulk loaded with `COPY ... FROM STDIN (FORMAT csv)`
+pub trait CopyRow {
+    /// Entity reported by [`Error::DuplicateRow`]
+    const ENTITY: Entity;
+    /// Target table
+    const TABLE: &'static str;
+    /// Columns in the order they are written by [`CopyRow::write_record`]
+    const COLUMNS: &'static str;
+    /// Conflict target used by [`OnConflict::Update`]
+    const KEY: &'static str = "id";
+    /// Other unique keys, rows clashing with an existing row on them are skipped by [`OnConflict::Update`]
+    const UNIQUE_KEYS: &'static [&'static str] = &[];
+    /// Columns refreshed by [`OnConflict::Update`], guarded by `updated_at`, see [`mutable_columns`]
+    const MUTABLE_COLUMNS: &'static [&'static str] = &[];
+
+    fn write_record(&self, record: &mut CopyRecord<'_>);
+
+    /// Existing row with the [`CopyRow::MUTABLE_COLUMNS`] of `newer` if it is more recent,
+    /// for storages refreshing rows without SQL
+    fn updated(&self, _newer: &Self) -> Option<Self>
+    where
+        Self: Sized,
+    {
+        None
+    }
+}
+
+/// Defines [`CopyRow::MUTABLE_COLUMNS`] and [`CopyRow::updated`] from the same fields,
+/// named as their columns
+macro_rules! mutable_columns {
+    ($($column:ident),+ $(,)?) => {
+        const MUTABLE_COLUMNS: &'static [&'static str] = &[$(stringify!($column)),+];
+
+        #[allow(clippy::clone_on_copy)]
+        fn updated(&self, newer: &Self) -> Option<Self> {
+            (self.updated_at < newer.updated_at).then(|| Self {
+                $($column: newer.$column.clone(),)+
+                ..self.clone()
+            })
+        }
+    };
+}
+
+pub(crate) use mutable_columns;
+
+/// How rows that already exist in the target table are handled
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OnConflict {
+    /// Abort on the first duplicate
+    #[default]
+    Fail,
+    /// Keep the existing row
+    Skip,
+    /// Refresh mutable columns of the existing row if the new one is more recent
+    Update,
+}
+
+impl OnConflict {
+    fn clause<R: CopyRow>(self) -> Option<String> {
+        match self {
+            OnConflict::Fail => None,
+            OnConflict::Update if !R::MUTABLE_COLUMNS.is_empty() => {
+                let columns = R::MUTABLE_COLUMNS
+                    .iter()
+                    .map(|column| format!("{column} = EXCLUDED.{column}", column = column))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                // The conflict target covers only the key, so rows clashing on other unique keys
+                // are left out up front, as `ON CONFLICT DO NOTHING` does for `Skip`
+                let clashes = R::UNIQUE_KEYS
+                    .iter()
+                    .map(|key| {
+                        let qualified = |table: &str| {
+                            key.split(", ")
+                                .map(|column| format!("{}.{}", table, column))
+                                .collect::<Vec<_>>()
+                                .join(", ")
+                        };
+                        format!(
+                            "NOT EXISTS (SELECT 1 FROM {table} existing \
+                             WHERE ({existing}) = ({staged}) AND existing.{key} <> staged.{key})",
+                            table = R::TABLE,
+                            existing = qualified("existing"),
+                            staged = qualified("staged"),
+                            key = R::KEY,
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                let filter = match clashes.is_empty() {
+                    true => String::new(),
+                    false => format!("WHERE {} ", clashes.join(" AND ")),
+                };
+                Some(format!(
+                    "{filter}ON CONFLICT ({key}) DO UPDATE SET {columns} \
+                     WHERE {table}.updated_at < EXCLUDED.updated_at",
+                    filter = filter,
+                    key = R::KEY,
+                    columns = columns,
+                    table = R::TABLE,
+                ))
+            }
+            OnConflict::Skip | OnConflict::Update => Some("ON CONFLICT DO NOTHING".to_owned()),
+        }
+    }
+}
+
+impl FromStr for OnConflict {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fail" => Ok(OnConflict::Fail),
+            "skip" => Ok(OnConflict::Skip),
+            "update" => Ok(OnConflict::Update),
+            _ => anyhow::bail!(
+                "Unknown conflict strategy `{}`, expected fail, skip or update",
+                s
+            ),
+        }
+    }
+}
+
+/// Single CSV line of `COPY` data
+pub struct CopyRecord<'a> {
+    buf: &'a mut Vec<u8>,
+    scratch: String,
+    first: bool,
+}
+
+impl<'a> CopyRecord<'a> {
+    fn new(buf: &'a mut Vec<u8>) -> Self {
+        Self {
+            buf,
+            scratch: String::new(),
+            first: true,
+        }
+    }
+
+    pub fn push<T: CopyField + ?Sized>(&mut self, value: &T) -> &mut Self {
+        if !self.first {
+            self.buf.push(b',');
+        }
+        self.first = false;
+
+        self.scratch.clear();
+        // Unquoted empty field is NULL in CSV format, so every value is quoted
+        if value.write_field(&mut self.scratch) {
+            self.buf.push(b'"');
+            for byte in self.scratch.bytes() {
+                if byte == b'"' {
+                    self.buf.push(b'"');
+                }
+                self.buf.push(byte);
+            }
+            self.buf.push(b'"');
+        }
+
+        self
+    }
+}
+
+/// Textual `COPY` representation of a column value
+pub trait CopyField {
+    /// Writes the value into `out`, returns `false` for `NULL`
+    fn write_field(&self, out: &mut String) -> bool;
+}
+
+impl<T: CopyField> CopyField for Option<T> {
+    fn write_field(&self, out: &mut String) -> bool {
+        match self {
+            Some(value) => value.write_field(out),
+            None => false,
+        }
+    }
+}
+
+impl CopyField for str {
+    fn write_field(&self, out: &mut String) -> bool {
+        out.push_str(self);
+        true
+    }
+}
+
+impl CopyField for String {
+    fn write_field(&self, out: &mut String) -> bool {
+        self.as_str().write_field(out)
+    }
+}
+
+impl CopyField for Vec<u8> {
+    fn write_field(&self, out: &mut String) -> bool {
+        out.push_str("\\x");
+        for byte in self {
+            let _ = write!(out, "{:02x}", byte);
+        }
+        true
+    }
+}
+
+impl CopyField for serde_json::Value {
+    fn write_field(&self, out: &mut String) -> bool {
+        let _ = write!(out, "{}", self);
+        true
+    }
+}
+
+macro_rules! impl_copy_field_display {
+    ($($ty:ty),*) => {
+        $(impl CopyField for $ty {
+            fn write_field(&self, out: &mut String) -> bool {
+                let _ = write!(out, "{}", self);
+                true
+            }
+        })*
+    };
+}
+
+impl_copy_field_display!(bool, i32, i64, BigDecimal, NaiveDateTime, Uuid, ServiceId);
+
+// Postgres enum labels match the variant names
+macro_rules! impl_copy_field_enum {
+    ($($ty:ty),*) => {
+        $(impl CopyField for $ty {
+            fn write_field(&self, out: &mut String) -> bool {
+                let _ = write!(out, "{:?}", self);
+                true
+            }
+        })*
+    };
+}
+
+impl_copy_field_enum!(
+    AccountType,
+    TonTransactionStatus,
+    TonTransactionDirection,
+    TonTokenTransactionStatus
+);
+
+/// Loads `rows` with a single `COPY` statement, returns the number of inserted or updated rows.
+///
+/// Rows are staged in a temporary table unless `on_conflict` is [`OnConflict::Fail`],
+/// which must be done inside a transaction.
+pub async fn copy_rows<R: CopyRow>(
+    conn: &mut PgConnection,
+    rows: &[R],
+    on_conflict: OnConflict,
+) -> Result<u64> {
+    if rows.is_empty() {
+        return Ok(0);
+    }
+
+    let mut data = Vec::with_capacity(rows.len() * 512);
+    for row in rows {
+        row.write_record(&mut CopyRecord::new(&mut data));
+        data.push(b'\n');
+    }
+
+    let on_conflict = match on_conflict.clause::<R>() {
+        Some(on_conflict) => on_conflict,
+        None => {
+            return copy_in(conn, R::TABLE, R::COLUMNS, data)
+                .await
+                .map_err(duplicate_row::<R>)
+        }
+    };
+
+    let staging = format!("{}_staging", R::TABLE);
+    sqlx::query(&format!(
+        "CREATE TEMPORARY TABLE IF NOT EXISTS {} (LIKE {} INCLUDING DEFAULTS) ON COMMIT DROP",
+        staging,
+        R::TABLE
+    ))
+    .execute(&mut *conn)
+    .await?;
+
+    copy_in(conn, &staging, R::COLUMNS, data).await?;
+
+    let inserted = sqlx::query(&format!(
+        "INSERT INTO {} ({columns}) SELECT {columns} FROM {} staged {}",
+        R::TABLE,
+        staging,
+        on_conflict,
+        columns = R::COLUMNS
+    ))
+    .execute(&mut *conn)
+    .await?
+    .rows_affected();
+
+    sqlx::query(&format!("TRUNCATE {}", staging))
+        .execute(&mut *conn)
+        .await?;
+
+    Ok(inserted)
+}
+
+async fn copy_in(
+    conn: &mut PgConnection,
+    table: &str,
+    columns: &str,
+    data: Vec<u8>,
+) -> Result<u64> {
+    let mut copy = conn
+        .copy_in_raw(&format!(
+            "COPY {} ({}) FROM STDIN WITH (FORMAT csv)",
+            table, columns
+        ))
+        .await?;
+    copy.send(data).await?;
+    copy.finish().await.map_err(From::from)
+}
+
+/// Turns a unique violation into [`Error::DuplicateRow`] with the key from its detail,
+/// e.g. `Key (id)=(...) already exists.` or `Key (workchain_id, hex)=(0, ...) already exists.`
+fn duplicate_row<R: CopyRow>(error: Error) -> Error {
+    let key = match &error {
+        Error::Database(e) => e
+            .as_database_error()
+            .and_then(|e| e.try_downcast_ref::<PgDatabaseError>())
+            .filter(|e| e.code() == "23505")
+            .and_then(|e| e.detail())
+            .and_then(|detail| {
+                let (columns, values) = detail.split_once(")=(")?;
+                let (_, columns) = columns.split_once('(')?;
+                let (values, _) = values.rsplit_once(')')?;
+                Some((columns.to_owned(), values.to_owned()))
+            }),
+        _ => None,
+    };
+
+    match key {
+        Some((key, id)) => Error::DuplicateRow {
+            entity: R::ENTITY,
+            key,
+            id,
+            file: None,
+            line: None,
+        },
+        None => error,
+    }
+}
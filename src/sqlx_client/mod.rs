@@ -1,10 +1,26 @@
-use sqlx::PgPool;
+use chrono::NaiveDateTime;
+use sqlx::{PgPool, Postgres, Transaction};
+
+use crate::error::Result;
+
+pub use self::copy::*;
 
 mod addresses;
+mod copy;
 mod token_owners;
 mod token_transactions;
 mod transactions;
 
+/// Rows of a service removed by a purge
+#[derive(Debug, Clone, Copy)]
+pub struct DeletedRows {
+    /// Rows of the service, including shared ones which were kept
+    pub rows: u64,
+    pub deleted: u64,
+    /// Latest `updated_at` (`created_at` for token owners) of the rows
+    pub last_updated_at: Option<NaiveDateTime>,
+}
+
 #[derive(Clone)]
 pub struct SqlxClient {
     pool: PgPool,
@@ -14,4 +30,24 @@ impl SqlxClient {
     pub fn new(pool: PgPool) -> SqlxClient {
         SqlxClient { pool }
     }
+
+    /// Name of the database and start time of its server, equal for any URL of the same database
+    pub async fn database_identity(&self) -> Result<String> {
+        sqlx::query_scalar("SELECT current_database() || ' ' || pg_postmaster_start_time()")
+            .fetch_one(&self.pool)
+            .await
+            .map_err(From::from)
+    }
+
+    /// Current time of the database server, in UTC like the stored times
+    pub async fn now(&self) -> Result<NaiveDateTime> {
+        sqlx::query_scalar("SELECT now() AT TIME ZONE 'UTC'")
+            .fetch_one(&self.pool)
+            .await
+            .map_err(From::from)
+    }
+
+    pub async fn begin(&self) -> Result<Transaction<'static, Postgres>> {
+        self.pool.begin().await.map_err(From::from)
+    }
 }
@@ -1,5 +1,8 @@
-use anyhow::Result;
+use futures::stream::BoxStream;
+use futures::{StreamExt, TryStreamExt};
+use sqlx::PgConnection;
 
+use crate::error::Result;
 use crate::models::*;
 use crate::sqlx_client::*;
 
@@ -40,4 +43,126 @@ impl SqlxClient {
 
         Ok(res)
     }
+
+    pub fn stream_all_token_owners(
+        &self,
+        service_id: ServiceId,
+        filter: &ExportFilter,
+    ) -> BoxStream<'_, Result<TokenOwnerDb>> {
+        let (workchain_ids, hexes) = filter.account_columns();
+        sqlx::query_as!(
+            TokenOwnerDb,
+            r#"SELECT t.address, t.owner_account_workchain_id, t.owner_account_hex, t.root_address, t.code_hash, t.created_at
+            FROM token_owners t
+            INNER JOIN address a ON a.workchain_id = t.owner_account_workchain_id AND a.hex = t.owner_account_hex
+            WHERE a.service_id = $1 AND ($2::timestamp IS NULL OR t.created_at >= $2) AND ($3::timestamp IS NULL OR t.created_at < $3) AND ($4::int[] IS NULL OR (t.owner_account_workchain_id, t.owner_account_hex) IN (SELECT * FROM UNNEST($4::int[], $5::varchar[])))
+            ORDER BY t.address COLLATE "C""#,
+            service_id as ServiceId,
+            filter.since,
+            filter.until,
+            workchain_ids.as_deref(),
+            hexes.as_deref(),
+        )
+            .fetch(&self.pool)
+            .map_err(From::from)
+            .boxed()
+    }
+
+    /// Addresses of the token owners of the service passing `filter` after `after`, at most `limit`
+    /// in byte order, the order of Rust strings
+    pub async fn get_token_owner_addresses_after(
+        &self,
+        service_id: ServiceId,
+        filter: &ExportFilter,
+        after: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<String>> {
+        let (workchain_ids, hexes) = filter.account_columns();
+        sqlx::query_scalar!(
+            r#"SELECT t.address
+            FROM token_owners t
+            INNER JOIN address a ON a.workchain_id = t.owner_account_workchain_id AND a.hex = t.owner_account_hex
+            WHERE a.service_id = $1 AND ($2::timestamp IS NULL OR t.created_at >= $2) AND ($3::timestamp IS NULL OR t.created_at < $3) AND ($4::int[] IS NULL OR (t.owner_account_workchain_id, t.owner_account_hex) IN (SELECT * FROM UNNEST($4::int[], $5::varchar[])))
+            AND ($6::varchar IS NULL OR t.address COLLATE "C" > $6)
+            ORDER BY t.address COLLATE "C" LIMIT $7"#,
+            service_id as ServiceId,
+            filter.since,
+            filter.until,
+            workchain_ids.as_deref(),
+            hexes.as_deref(),
+            after,
+            limit,
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(From::from)
+    }
+
+    pub async fn get_token_owners_by_addresses(
+        &self,
+        addresses: &[String],
+    ) -> Result<Vec<TokenOwnerDb>> {
+        sqlx::query_as!(
+            TokenOwnerDb,
+            r#"SELECT address, owner_account_workchain_id, owner_account_hex, root_address, code_hash, created_at
+            FROM token_owners
+            WHERE address = ANY($1)"#,
+            addresses,
+        )
+            .fetch_all(&self.pool)
+            .await
+            .map_err(From::from)
+    }
+
+    /// Deletes token owners of addresses of the service, owners whose account is also
+    /// an address of another service are kept
+    pub async fn delete_token_owners(
+        &self,
+        conn: &mut PgConnection,
+        service_id: ServiceId,
+    ) -> Result<DeletedRows> {
+        let res = sqlx::query!(
+            r#"WITH owners AS (
+                SELECT t.address, t.created_at, EXISTS (
+                    SELECT 1 FROM address o
+                    WHERE o.workchain_id = t.owner_account_workchain_id AND o.hex = t.owner_account_hex AND o.service_id <> $1
+                ) AS shared
+                FROM token_owners t
+                JOIN address a ON a.workchain_id = t.owner_account_workchain_id AND a.hex = t.owner_account_hex
+                WHERE a.service_id = $1
+            ), deleted AS (
+                DELETE FROM token_owners t USING owners o WHERE t.address = o.address AND NOT o.shared
+                RETURNING t.address
+            )
+            SELECT (SELECT count(*) FROM owners) AS "rows!", (SELECT count(*) FROM deleted) AS "deleted!",
+                (SELECT max(created_at) FROM owners) AS last_updated_at"#,
+            service_id as ServiceId,
+        )
+        .fetch_one(&mut *conn)
+        .await?;
+
+        Ok(DeletedRows {
+            rows: res.rows as u64,
+            deleted: res.deleted as u64,
+            last_updated_at: res.last_updated_at,
+        })
+    }
+}
+
+impl CopyRow for TokenOwnerDb {
+    const ENTITY: Entity = Entity::TokenOwners;
+    const TABLE: &'static str = "token_owners";
+    const COLUMNS: &'static str =
+        "address, owner_account_workchain_id, owner_account_hex, root_address, code_hash, created_at";
+    const KEY: &'static str = "address";
+
+    fn write_record(&self, record: &mut CopyRecord<'_>) {
+        record
+            .push(&self.address)
+            .push(&self.owner_account_workchain_id)
+            .push(&self.owner_account_hex)
+            .push(&self.root_address)
+            .push(&self.code_hash)
+            .push(&self.created_at);
+    }
 }
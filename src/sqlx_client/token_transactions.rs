@@ -1,5 +1,9 @@
-use anyhow::Result;
+use futures::stream::BoxStream;
+use futures::{StreamExt, TryStreamExt};
+use sqlx::PgConnection;
+use uuid::Uuid;
 
+use crate::error::Result;
 use crate::models::*;
 use crate::sqlx_client::*;
 
@@ -54,4 +58,131 @@ impl SqlxClient {
             .await
             .map_err(From::from)
     }
+
+    pub fn stream_all_token_transactions(
+        &self,
+        service_id: ServiceId,
+        filter: &ExportFilter,
+    ) -> BoxStream<'_, Result<TokenTransactionDb>> {
+        let (workchain_ids, hexes) = filter.account_columns();
+        sqlx::query_as!(TokenTransactionDb, r#"SELECT id, service_id as "service_id: _", transaction_hash, transaction_timestamp, message_hash,
+            owner_message_hash, account_workchain_id, account_hex, value, root_address, payload, error, block_hash, block_time, direction as "direction: _",
+            status as "status: _", created_at, updated_at
+            FROM token_transactions
+            WHERE service_id = $1 AND ($2::timestamp IS NULL OR updated_at >= $2) AND ($3::timestamp IS NULL OR updated_at < $3) AND ($4::int[] IS NULL OR (account_workchain_id, account_hex) IN (SELECT * FROM UNNEST($4::int[], $5::varchar[])))
+            ORDER BY id"#,
+            service_id as ServiceId,
+            filter.since,
+            filter.until,
+            workchain_ids.as_deref(),
+            hexes.as_deref(),
+        )
+            .fetch(&self.pool)
+            .map_err(From::from)
+            .boxed()
+    }
+
+    /// Ids of the token transactions of the service passing `filter` after `after`, at most `limit` in id order
+    pub async fn get_token_transaction_ids_after(
+        &self,
+        service_id: ServiceId,
+        filter: &ExportFilter,
+        after: Option<Uuid>,
+        limit: i64,
+    ) -> Result<Vec<Uuid>> {
+        let (workchain_ids, hexes) = filter.account_columns();
+        sqlx::query_scalar!(
+            r#"SELECT id FROM token_transactions
+            WHERE service_id = $1 AND ($2::timestamp IS NULL OR updated_at >= $2) AND ($3::timestamp IS NULL OR updated_at < $3) AND ($4::int[] IS NULL OR (account_workchain_id, account_hex) IN (SELECT * FROM UNNEST($4::int[], $5::varchar[])))
+            AND ($6::uuid IS NULL OR id > $6)
+            ORDER BY id LIMIT $7"#,
+            service_id as ServiceId,
+            filter.since,
+            filter.until,
+            workchain_ids.as_deref(),
+            hexes.as_deref(),
+            after,
+            limit,
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(From::from)
+    }
+
+    pub async fn get_token_transactions_by_ids(
+        &self,
+        ids: &[Uuid],
+    ) -> Result<Vec<TokenTransactionDb>> {
+        sqlx::query_as!(TokenTransactionDb, r#"SELECT id, service_id as "service_id: _", transaction_hash, transaction_timestamp, message_hash,
+            owner_message_hash, account_workchain_id, account_hex, value, root_address, payload, error, block_hash, block_time, direction as "direction: _",
+            status as "status: _", created_at, updated_at
+            FROM token_transactions
+            WHERE id = ANY($1)"#,
+            ids,
+        )
+            .fetch_all(&self.pool)
+            .await
+            .map_err(From::from)
+    }
+
+    /// Deletes all token transactions of the service
+    pub async fn delete_token_transactions(
+        &self,
+        conn: &mut PgConnection,
+        service_id: ServiceId,
+    ) -> Result<DeletedRows> {
+        let res = sqlx::query!(
+            r#"WITH deleted AS (DELETE FROM token_transactions WHERE service_id = $1 RETURNING updated_at)
+            SELECT count(*) AS "rows!", max(updated_at) AS last_updated_at FROM deleted"#,
+            service_id as ServiceId,
+        )
+        .fetch_one(&mut *conn)
+        .await?;
+
+        Ok(DeletedRows {
+            rows: res.rows as u64,
+            deleted: res.rows as u64,
+            last_updated_at: res.last_updated_at,
+        })
+    }
+}
+
+impl CopyRow for TokenTransactionDb {
+    const ENTITY: Entity = Entity::TokenTransactions;
+    const TABLE: &'static str = "token_transactions";
+    const COLUMNS: &'static str = "id, service_id, transaction_hash, transaction_timestamp, message_hash, owner_message_hash, \
+        account_workchain_id, account_hex, value, root_address, payload, error, block_hash, block_time, direction, status, \
+        created_at, updated_at";
+    mutable_columns!(
+        transaction_hash,
+        transaction_timestamp,
+        owner_message_hash,
+        error,
+        block_hash,
+        block_time,
+        status,
+        updated_at,
+    );
+
+    fn write_record(&self, record: &mut CopyRecord<'_>) {
+        record
+            .push(&self.id)
+            .push(&self.service_id)
+            .push(&self.transaction_hash)
+            .push(&self.transaction_timestamp)
+            .push(&self.message_hash)
+            .push(&self.owner_message_hash)
+            .push(&self.account_workchain_id)
+            .push(&self.account_hex)
+            .push(&self.value)
+            .push(&self.root_address)
+            .push(&self.payload)
+            .push(&self.error)
+            .push(&self.block_hash)
+            .push(&self.block_time)
+            .push(&self.direction)
+            .push(&self.status)
+            .push(&self.created_at)
+            .push(&self.updated_at);
+    }
 }
@@ -1,5 +1,9 @@
-use anyhow::Result;
+use futures::stream::BoxStream;
+use futures::{StreamExt, TryStreamExt};
+use sqlx::PgConnection;
+use uuid::Uuid;
 
+use crate::error::Result;
 use crate::models::*;
 use crate::sqlx_client::*;
 
@@ -62,4 +66,149 @@ impl SqlxClient {
             .await
             .map_err(From::from)
     }
+
+    pub fn stream_all_transactions(
+        &self,
+        service_id: ServiceId,
+        filter: &ExportFilter,
+    ) -> BoxStream<'_, Result<TransactionDb>> {
+        let (workchain_ids, hexes) = filter.account_columns();
+        sqlx::query_as!(TransactionDb, r#"SELECT id, service_id as "service_id: _", message_hash, transaction_hash, transaction_lt, transaction_timeout,
+                transaction_scan_lt, transaction_timestamp, sender_workchain_id, sender_hex, account_workchain_id, account_hex, messages, messages_hash, data,
+                original_value, original_outputs, value, fee, balance_change, direction as "direction: _", status as "status: _",
+                error, aborted, bounce, created_at, updated_at
+                FROM transactions
+                WHERE service_id = $1 AND ($2::timestamp IS NULL OR updated_at >= $2) AND ($3::timestamp IS NULL OR updated_at < $3) AND ($4::int[] IS NULL OR (account_workchain_id, account_hex) IN (SELECT * FROM UNNEST($4::int[], $5::varchar[])))
+                ORDER BY id"#,
+                service_id as ServiceId,
+                filter.since,
+                filter.until,
+                workchain_ids.as_deref(),
+                hexes.as_deref(),
+        )
+            .fetch(&self.pool)
+            .map_err(From::from)
+            .boxed()
+    }
+
+    /// Ids of the transactions of the service passing `filter` after `after`, at most `limit` in id order
+    pub async fn get_transaction_ids_after(
+        &self,
+        service_id: ServiceId,
+        filter: &ExportFilter,
+        after: Option<Uuid>,
+        limit: i64,
+    ) -> Result<Vec<Uuid>> {
+        let (workchain_ids, hexes) = filter.account_columns();
+        sqlx::query_scalar!(
+            r#"SELECT id FROM transactions
+            WHERE service_id = $1 AND ($2::timestamp IS NULL OR updated_at >= $2) AND ($3::timestamp IS NULL OR updated_at < $3) AND ($4::int[] IS NULL OR (account_workchain_id, account_hex) IN (SELECT * FROM UNNEST($4::int[], $5::varchar[])))
+            AND ($6::uuid IS NULL OR id > $6)
+            ORDER BY id LIMIT $7"#,
+            service_id as ServiceId,
+            filter.since,
+            filter.until,
+            workchain_ids.as_deref(),
+            hexes.as_deref(),
+            after,
+            limit,
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(From::from)
+    }
+
+    pub async fn get_transactions_by_ids(&self, ids: &[Uuid]) -> Result<Vec<TransactionDb>> {
+        sqlx::query_as!(TransactionDb, r#"SELECT id, service_id as "service_id: _", message_hash, transaction_hash, transaction_lt, transaction_timeout,
+                transaction_scan_lt, transaction_timestamp, sender_workchain_id, sender_hex, account_workchain_id, account_hex, messages, messages_hash, data,
+                original_value, original_outputs, value, fee, balance_change, direction as "direction: _", status as "status: _",
+                error, aborted, bounce, created_at, updated_at
+                FROM transactions WHERE id = ANY($1)"#,
+                ids,
+        )
+            .fetch_all(&self.pool)
+            .await
+            .map_err(From::from)
+    }
+
+    /// Deletes all transactions of the service
+    pub async fn delete_transactions(
+        &self,
+        conn: &mut PgConnection,
+        service_id: ServiceId,
+    ) -> Result<DeletedRows> {
+        let res = sqlx::query!(
+            r#"WITH deleted AS (DELETE FROM transactions WHERE service_id = $1 RETURNING updated_at)
+            SELECT count(*) AS "rows!", max(updated_at) AS last_updated_at FROM deleted"#,
+            service_id as ServiceId,
+        )
+        .fetch_one(&mut *conn)
+        .await?;
+
+        Ok(DeletedRows {
+            rows: res.rows as u64,
+            deleted: res.rows as u64,
+            last_updated_at: res.last_updated_at,
+        })
+    }
+}
+
+impl CopyRow for TransactionDb {
+    const ENTITY: Entity = Entity::Transactions;
+    const TABLE: &'static str = "transactions";
+    const COLUMNS: &'static str = "id, service_id, message_hash, transaction_hash, transaction_lt, transaction_timeout, \
+        transaction_scan_lt, transaction_timestamp, sender_workchain_id, sender_hex, account_workchain_id, account_hex, \
+        messages, messages_hash, data, original_value, original_outputs, value, fee, balance_change, direction, status, \
+        error, aborted, bounce, created_at, updated_at";
+    mutable_columns!(
+        transaction_hash,
+        transaction_lt,
+        transaction_timeout,
+        transaction_scan_lt,
+        transaction_timestamp,
+        messages,
+        messages_hash,
+        data,
+        original_value,
+        original_outputs,
+        value,
+        fee,
+        balance_change,
+        status,
+        error,
+        aborted,
+        bounce,
+        updated_at,
+    );
+
+    fn write_record(&self, record: &mut CopyRecord<'_>) {
+        record
+            .push(&self.id)
+            .push(&self.service_id)
+            .push(&self.message_hash)
+            .push(&self.transaction_hash)
+            .push(&self.transaction_lt)
+            .push(&self.transaction_timeout)
+            .push(&self.transaction_scan_lt)
+            .push(&self.transaction_timestamp)
+            .push(&self.sender_workchain_id)
+            .push(&self.sender_hex)
+            .push(&self.account_workchain_id)
+            .push(&self.account_hex)
+            .push(&self.messages)
+            .push(&self.messages_hash)
+            .push(&self.data)
+            .push(&self.original_value)
+            .push(&self.original_outputs)
+            .push(&self.value)
+            .push(&self.fee)
+            .push(&self.balance_change)
+            .push(&self.direction)
+            .push(&self.status)
+            .push(&self.error)
+            .push(&self.aborted)
+            .push(&self.bounce)
+            .push(&self.created_at)
+            .push(&self.updated_at);
+    }
 }
@@ -0,0 +1,98 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+
+/// Writer which must be finished explicitly, e.g. to write a trailer or sync to disk
+pub trait FinishWrite: Write + Send {
+    fn finish_write(self: Box<Self>) -> io::Result<()>;
+}
+
+/// Files are synced to disk when finished
+impl FinishWrite for File {
+    fn finish_write(self: Box<Self>) -> io::Result<()> {
+        self.sync_all()
+    }
+}
+
+impl FinishWrite for Box<dyn FinishWrite> {
+    fn finish_write(self: Box<Self>) -> io::Result<()> {
+        (*self).finish_write()
+    }
+}
+
+impl<W: FinishWrite> FinishWrite for GzEncoder<W> {
+    fn finish_write(self: Box<Self>) -> io::Result<()> {
+        Box::new(self.finish()?).finish_write()
+    }
+}
+
+impl<W: FinishWrite> FinishWrite for zstd::Encoder<'static, W> {
+    fn finish_write(self: Box<Self>) -> io::Result<()> {
+        Box::new(self.finish()?).finish_write()
+    }
+}
+
+/// Compression of bundle files, detected on import by the file extension
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    pub const ALL: [Compression; 3] = [Compression::None, Compression::Gzip, Compression::Zstd];
+
+    /// Suffix appended to the file name
+    pub fn extension(self) -> &'static str {
+        match self {
+            Compression::None => "",
+            Compression::Gzip => ".gz",
+            Compression::Zstd => ".zst",
+        }
+    }
+
+    pub fn encoder(self, inner: Box<dyn FinishWrite>) -> io::Result<Box<dyn FinishWrite>> {
+        Ok(match self {
+            Compression::None => inner,
+            Compression::Gzip => Box::new(GzEncoder::new(inner, flate2::Compression::default())),
+            Compression::Zstd => Box::new(zstd::Encoder::new(inner, 0)?),
+        })
+    }
+
+    pub fn decoder<'a>(self, inner: Box<dyn Read + 'a>) -> io::Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            Compression::None => inner,
+            Compression::Gzip => Box::new(MultiGzDecoder::new(inner)),
+            Compression::Zstd => Box::new(zstd::Decoder::new(inner)?),
+        })
+    }
+}
+
+impl FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            _ => anyhow::bail!("Unknown compression `{}`, expected none, gzip or zstd", s),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Compression::None => "none",
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+        })
+    }
+}
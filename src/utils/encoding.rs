@@ -1,25 +1,70 @@
-use anyhow::{Error, Result};
+use anyhow::Context;
+use argon2::password_hash::Salt;
 use chacha20poly1305::aead::AeadMut;
 use chacha20poly1305::{ChaCha20Poly1305, Nonce};
+use zeroize::Zeroizing;
 
-pub fn encrypt(private_key: &str, key: [u8; 32], id: &uuid::Uuid) -> Result<String> {
+use crate::error::*;
+use crate::models::*;
+
+/// Derives the private keys encryption key from the service secret and salt,
+/// the key is zeroized when dropped
+pub fn derive_key(secret: &str, salt: &str, params: &KdfParams) -> Result<Zeroizing<[u8; 32]>> {
+    let mut options = argon2::ParamsBuilder::default();
+    let options = options
+        .m_cost(params.memory_cost)
+        .and_then(|x| x.t_cost(params.iterations))
+        .and_then(|x| x.p_cost(params.parallelism))
+        .and_then(|x| x.output_len(32)) //chacha key size
+        .and_then(|x| x.clone().params())
+        .map_err(anyhow::Error::msg)
+        .with_context(|| format!("Invalid KDF parameters {}", params))?;
+
+    let algorithm = match params.variant {
+        Argon2Variant::Argon2d => argon2::Algorithm::Argon2d,
+        Argon2Variant::Argon2i => argon2::Algorithm::Argon2i,
+        Argon2Variant::Argon2id => argon2::Algorithm::Argon2id,
+    };
+    let argon2 = argon2::Argon2::new(algorithm, argon2::Version::V0x13, options);
+
+    // Salt is B64 encoded, the same way `PasswordHasher::hash_password` treats it
+    let mut salt_buf = [0u8; 64];
+    let salt = Salt::new(salt)
+        .and_then(|salt| salt.b64_decode(&mut salt_buf))
+        .map_err(anyhow::Error::msg)?;
+
+    let mut key = Zeroizing::new([0u8; 32]);
+    argon2
+        .hash_password_into(secret.as_bytes(), salt, &mut key[..])
+        .map_err(anyhow::Error::msg)?;
+
+    Ok(key)
+}
+
+pub fn encrypt(private_key: &str, key: &[u8; 32], id: &uuid::Uuid) -> Result<String> {
     use chacha20poly1305::aead::NewAead;
     let nonce = Nonce::from_slice(&id.as_bytes()[0..12]);
     let key = chacha20poly1305::Key::from_slice(&key[..]);
     let mut encryptor = ChaCha20Poly1305::new(key);
+    let private_key = base64::decode(private_key)
+        .map_err(|source| Error::InvalidPrivateKey { id: *id, source })?;
     let res = encryptor
-        .encrypt(nonce, base64::decode(&private_key)?.as_slice())
-        .unwrap();
+        .encrypt(nonce, private_key.as_slice())
+        .map_err(|_| Error::Encryption { id: *id })?;
 
     Ok(base64::encode(res))
 }
 
-pub fn decrypt(private_key: &str, key: [u8; 32], id: &uuid::Uuid) -> Result<Vec<u8>> {
+/// Decrypted private key, zeroized once dropped
+pub fn decrypt(private_key: &str, key: &[u8; 32], id: &uuid::Uuid) -> Result<Zeroizing<Vec<u8>>> {
     use chacha20poly1305::aead::NewAead;
     let nonce = Nonce::from_slice(&id.as_bytes()[0..12]);
     let key = chacha20poly1305::Key::from_slice(&key[..]);
     let mut decrypter = ChaCha20Poly1305::new(key);
+    let private_key = base64::decode(private_key)
+        .map_err(|source| Error::InvalidPrivateKey { id: *id, source })?;
     decrypter
-        .decrypt(nonce, base64::decode(private_key)?.as_slice())
-        .map_err(Error::msg)
+        .decrypt(nonce, private_key.as_slice())
+        .map(Zeroizing::new)
+        .map_err(|_| Error::Decryption { id: *id })
 }
@@ -0,0 +1,53 @@
+use std::io::{Read, Write};
+
+use sha2::{Digest, Sha256};
+
+/// Computes SHA-256 of everything written through it
+pub struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// Returns the inner writer and the hex encoded digest
+    pub fn finish(self) -> (W, String) {
+        (self.inner, hex::encode(self.hasher.finalize()))
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Returns the hex encoded SHA-256 and the number of lines of `reader`
+pub fn hash_lines<R: Read>(mut reader: R) -> std::io::Result<(String, u64)> {
+    let mut hasher = Sha256::new();
+    let mut lines = 0;
+
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let read = reader.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+        lines += buf[..read].iter().filter(|&&byte| byte == b'\n').count() as u64;
+    }
+
+    Ok((hex::encode(hasher.finalize()), lines))
+}
@@ -0,0 +1,44 @@
+use sha2::{Digest, Sha256};
+
+use crate::error::*;
+use crate::models::*;
+use crate::repository::*;
+use crate::utils::*;
+
+/// Short digest identifying a derived key, safe to store next to the service config
+pub fn key_check(key: &[u8; 32]) -> String {
+    let digest = Sha256::new()
+        .chain(b"ton-api-utility key check")
+        .chain(key)
+        .finalize();
+    hex::encode(&digest[..8])
+}
+
+/// Fails with a wrong secret error unless `key` matches `expected_check` and
+/// decrypts an existing address of the service
+pub async fn check_key<S: Repository>(
+    repository: &S,
+    service_id: ServiceId,
+    key: &[u8; 32],
+    expected_check: Option<&str>,
+) -> Result<()> {
+    if let Some(expected_check) = expected_check {
+        if !key_check(key).eq_ignore_ascii_case(expected_check) {
+            return Err(Error::WrongKey {
+                service_id,
+                reason: format!("key check value doesn't match {}", expected_check),
+            });
+        }
+    }
+
+    if let Some(address) = repository.get_any_address(service_id).await? {
+        if decrypt(&address.private_key, key, &address.id).is_err() {
+            return Err(Error::WrongKey {
+                service_id,
+                reason: format!("it doesn't decrypt address {}", address.id),
+            });
+        }
+    }
+
+    Ok(())
+}
@@ -1,5 +1,15 @@
+pub use self::compression::*;
 pub use self::encoding::*;
+pub use self::hashing::*;
+pub use self::key_check::*;
 pub use self::pg_pool::*;
+pub use self::secret::*;
+pub use self::transport::*;
 
+mod compression;
 mod encoding;
+mod hashing;
+mod key_check;
 mod pg_pool;
+mod secret;
+mod transport;
@@ -1,18 +1,24 @@
 use std::env;
 
-use anyhow::{Context, Result};
+use anyhow::Context;
 use sqlx::postgres::PgPoolOptions;
 use sqlx::{Pool, Postgres};
 
+use crate::error::Result;
+
 const DATABASE_CONNECTOINS: u32 = 1;
 
 pub async fn get_pg_pool() -> Result<Pool<Postgres>> {
     let database_url =
         env::var("DATABASE_URL").context("The DATABASE_URL environment variable must be set")?;
 
+    get_pg_pool_for(&database_url).await
+}
+
+pub async fn get_pg_pool_for(database_url: &str) -> Result<Pool<Postgres>> {
     PgPoolOptions::new()
         .max_connections(DATABASE_CONNECTOINS)
-        .connect(&database_url)
+        .connect(database_url)
         .await
-        .context("fail pg pool")
+        .map_err(From::from)
 }
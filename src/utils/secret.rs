@@ -0,0 +1,76 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use zeroize::Zeroizing;
+
+/// Where a secret is read from, so it doesn't end up in shell history or `ps` output
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SecretSource {
+    /// `env:NAME`, environment variable
+    Env(String),
+    /// `file:PATH`, first line of a file
+    File(PathBuf),
+    /// `fd:N`, first line read from an inherited file descriptor
+    Fd(u32),
+    /// `prompt`, interactive input without echo
+    Prompt,
+}
+
+impl SecretSource {
+    /// Reads the secret, `name` is used in the prompt and errors
+    pub fn read(&self, name: &str) -> Result<Zeroizing<String>> {
+        let secret = match self {
+            SecretSource::Env(var) => Zeroizing::new(
+                std::env::var(var)
+                    .with_context(|| format!("Failed to read {} from ${}", name, var))?,
+            ),
+            SecretSource::File(path) => first_line(
+                std::fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read {} from {}", name, path.display()))?,
+            ),
+            SecretSource::Fd(fd) => first_line(
+                std::fs::read_to_string(format!("/dev/fd/{}", fd))
+                    .with_context(|| format!("Failed to read {} from fd {}", name, fd))?,
+            ),
+            SecretSource::Prompt => Zeroizing::new(
+                rpassword::prompt_password(format!("{}: ", name))
+                    .with_context(|| format!("Failed to read {} from the terminal", name))?,
+            ),
+        };
+
+        if secret.is_empty() {
+            anyhow::bail!("Empty {}", name);
+        }
+
+        Ok(secret)
+    }
+}
+
+impl FromStr for SecretSource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("env", var)) => Ok(SecretSource::Env(var.to_owned())),
+            Some(("file", path)) => Ok(SecretSource::File(PathBuf::from(path))),
+            Some(("fd", fd)) => {
+                Ok(SecretSource::Fd(fd.parse().with_context(|| {
+                    format!("Invalid file descriptor `{}`", fd)
+                })?))
+            }
+            None if s == "prompt" => Ok(SecretSource::Prompt),
+            _ => anyhow::bail!(
+                "Unknown secret source `{}`, expected env:NAME, file:PATH, fd:N or prompt",
+                s
+            ),
+        }
+    }
+}
+
+/// Drops the trailing newline and anything after it, zeroizing the whole input
+fn first_line(contents: String) -> Zeroizing<String> {
+    let contents = Zeroizing::new(contents);
+    let line = contents.lines().next().unwrap_or_default();
+    Zeroizing::new(line.to_owned())
+}
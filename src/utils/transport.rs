@@ -0,0 +1,186 @@
+use std::io::{self, Read, Write};
+
+use anyhow::Result;
+use chacha20poly1305::aead::stream::{DecryptorBE32, EncryptorBE32};
+use chacha20poly1305::aead::NewAead;
+use chacha20poly1305::ChaCha20Poly1305;
+use serde::{Deserialize, Serialize};
+
+use crate::models::*;
+use crate::utils::*;
+
+/// Plaintext bytes sealed into a single STREAM segment
+const CHUNK_SIZE: usize = 64 * 1024;
+/// Poly1305 tag appended to every segment
+const TAG_SIZE: usize = 16;
+/// STREAM nonce prefix length for a 96-bit nonce with a 32-bit counter and a last block flag
+const NONCE_PREFIX_SIZE: usize = 7;
+/// Argon2id costs of new transport keys: a passphrase is weaker than the service secret,
+/// and the key is derived once per file
+const TRANSPORT_KDF: KdfParams = KdfParams {
+    variant: Argon2Variant::Argon2id,
+    memory_cost: 64 * 1024,
+    iterations: 3,
+    parallelism: 1,
+};
+
+/// Parameters of a bundle file encrypted with a transport passphrase
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TransportEncryption {
+    /// Hex encoded Argon2 salt of the transport key
+    pub salt: String,
+    /// Hex encoded STREAM nonce prefix
+    pub nonce: String,
+    /// Argon2 parameters of the transport key, bundles without them used the defaults
+    #[serde(default)]
+    pub kdf: KdfParams,
+}
+
+impl TransportEncryption {
+    /// Fresh salt and nonce, a new pair is generated for every file
+    pub fn generate() -> Self {
+        Self {
+            salt: hex::encode(rand::random::<[u8; 16]>()),
+            nonce: hex::encode(rand::random::<[u8; NONCE_PREFIX_SIZE]>()),
+            kdf: TRANSPORT_KDF,
+        }
+    }
+
+    pub fn encryptor<W: Write>(&self, passphrase: &str, inner: W) -> Result<EncryptingWriter<W>> {
+        let (cipher, nonce) = self.cipher(passphrase)?;
+        Ok(EncryptingWriter {
+            inner,
+            encryptor: Some(EncryptorBE32::from_aead(cipher, nonce.as_slice().into())),
+            buf: Vec::with_capacity(CHUNK_SIZE),
+        })
+    }
+
+    pub fn decryptor<R: Read>(&self, passphrase: &str, inner: R) -> Result<DecryptingReader<R>> {
+        let (cipher, nonce) = self.cipher(passphrase)?;
+        Ok(DecryptingReader {
+            inner,
+            decryptor: Some(DecryptorBE32::from_aead(cipher, nonce.as_slice().into())),
+            plain: Vec::new(),
+            pos: 0,
+        })
+    }
+
+    fn cipher(&self, passphrase: &str) -> Result<(ChaCha20Poly1305, Vec<u8>)> {
+        let nonce = hex::decode(&self.nonce)?;
+        if nonce.len() != NONCE_PREFIX_SIZE {
+            anyhow::bail!("Invalid transport nonce length {}", nonce.len());
+        }
+
+        let key = derive_key(passphrase, &self.salt, &self.kdf)?;
+        let cipher = ChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(&key[..]));
+
+        Ok((cipher, nonce))
+    }
+}
+
+/// Encrypts everything written through it in fixed size segments, see [`TransportEncryption`]
+pub struct EncryptingWriter<W: Write> {
+    inner: W,
+    encryptor: Option<EncryptorBE32<ChaCha20Poly1305>>,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> EncryptingWriter<W> {
+    /// Seals the last segment and returns the inner writer
+    pub fn finish(mut self) -> io::Result<W> {
+        // Last segment is always shorter than a full one, possibly empty
+        let encryptor = self.encryptor.take().ok_or_else(encryption_error)?;
+        let segment = encryptor
+            .encrypt_last(self.buf.as_slice())
+            .map_err(|_| encryption_error())?;
+        self.inner.write_all(&segment)?;
+        self.inner.flush()?;
+
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for EncryptingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+
+        while self.buf.len() >= CHUNK_SIZE {
+            let encryptor = self.encryptor.as_mut().ok_or_else(encryption_error)?;
+            let segment = encryptor
+                .encrypt_next(&self.buf[..CHUNK_SIZE])
+                .map_err(|_| encryption_error())?;
+            self.inner.write_all(&segment)?;
+            self.buf.drain(..CHUNK_SIZE);
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: FinishWrite> FinishWrite for EncryptingWriter<W> {
+    fn finish_write(self: Box<Self>) -> io::Result<()> {
+        Box::new(self.finish()?).finish_write()
+    }
+}
+
+/// Decrypts a file written by [`EncryptingWriter`], failing on truncated or modified input
+pub struct DecryptingReader<R: Read> {
+    inner: R,
+    decryptor: Option<DecryptorBE32<ChaCha20Poly1305>>,
+    plain: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> DecryptingReader<R> {
+    fn next_segment(&mut self) -> io::Result<()> {
+        let mut segment = Vec::with_capacity(CHUNK_SIZE + TAG_SIZE);
+        (&mut self.inner)
+            .take((CHUNK_SIZE + TAG_SIZE) as u64)
+            .read_to_end(&mut segment)?;
+
+        let plain = if segment.len() == CHUNK_SIZE + TAG_SIZE {
+            let decryptor = self.decryptor.as_mut().ok_or_else(decryption_error)?;
+            decryptor.decrypt_next(segment.as_slice())
+        } else {
+            let decryptor = self.decryptor.take().ok_or_else(decryption_error)?;
+            decryptor.decrypt_last(segment.as_slice())
+        };
+
+        self.plain = plain.map_err(|_| decryption_error())?;
+        self.pos = 0;
+
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.plain.len() {
+            if self.decryptor.is_none() {
+                return Ok(0);
+            }
+            self.next_segment()?;
+        }
+
+        let len = buf.len().min(self.plain.len() - self.pos);
+        buf[..len].copy_from_slice(&self.plain[self.pos..self.pos + len]);
+        self.pos += len;
+
+        Ok(len)
+    }
+}
+
+fn encryption_error() -> io::Error {
+    io::Error::other("Failed to encrypt bundle file")
+}
+
+fn decryption_error() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "Failed to decrypt bundle file, wrong transport passphrase or corrupted file",
+    )
+}
@@ -0,0 +1,464 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::hash::Hash;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use bigdecimal::BigDecimal;
+use serde::Serialize;
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+use crate::bundle::*;
+use crate::models::*;
+use crate::sqlx_client::*;
+use crate::utils::*;
+
+/// Rows looked up in the database with a single query, also the size of a page of database keys
+const LOOKUP_BATCH_SIZE: usize = 1_000;
+
+/// Differing rows listed in the report for each kind of difference
+const MAX_REPORTED_ROWS: usize = 100;
+
+/// Compares the bundle at `path` with the database, `service_id` overrides the exported one
+pub async fn run_verify(
+    service_id: Option<ServiceId>,
+    path: PathBuf,
+    key: Zeroizing<[u8; 32]>,
+    transport_passphrase: Option<&str>,
+) -> Result<VerifyReport> {
+    let bundle = BundleReader::open(path, transport_passphrase)?;
+    let manifest = bundle.manifest();
+    let entities = manifest.entities();
+    bundle
+        .verify(&entities)
+        .context("Bundle verification failed")?;
+
+    let pool = get_pg_pool().await?;
+    let sqlx_client = SqlxClient::new(pool);
+
+    let verifier = Verifier {
+        sqlx_client: &sqlx_client,
+        bundle: &bundle,
+        service_id: service_id.unwrap_or(manifest.service_id),
+        service_id_override: service_id,
+        filter: ExportFilter {
+            since: manifest.since,
+            // Rows written after the export started are left to the next one, as purge does
+            until: manifest.until.or(manifest.watermark),
+            accounts: manifest.accounts.clone(),
+        },
+        key: &key,
+    };
+
+    let mut files = Vec::with_capacity(entities.len());
+    for entity in entities {
+        files.push(match entity {
+            Entity::Addresses => verifier.verify_file::<AddressDb>().await?,
+            Entity::Transactions => verifier.verify_file::<TransactionDb>().await?,
+            Entity::TokenOwners => verifier.verify_file::<TokenOwnerDb>().await?,
+            Entity::TokenTransactions => verifier.verify_file::<TokenTransactionDb>().await?,
+        });
+    }
+
+    Ok(VerifyReport { files })
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct VerifyReport {
+    pub files: Vec<FileReport>,
+}
+
+impl VerifyReport {
+    pub fn is_consistent(&self) -> bool {
+        self.files.iter().all(FileReport::is_consistent)
+    }
+}
+
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.files.iter().try_for_each(|file| file.fmt(f))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FileReport {
+    pub file: String,
+    /// Rows read from the bundle
+    pub checked: u64,
+    /// Bundle rows absent in the database
+    pub missing: Differences,
+    /// Database rows of the exported range absent in the bundle
+    pub extra: Differences,
+    /// Rows present in both with different fields
+    pub mismatched: Differences,
+}
+
+impl FileReport {
+    pub fn is_consistent(&self) -> bool {
+        self.missing.count == 0 && self.extra.count == 0 && self.mismatched.count == 0
+    }
+}
+
+impl fmt::Display for FileReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{}: {} checked, {} missing, {} extra, {} mismatched",
+            self.file, self.checked, self.missing.count, self.extra.count, self.mismatched.count
+        )?;
+        self.missing.fmt_samples(f, "missing")?;
+        self.extra.fmt_samples(f, "extra")?;
+        self.mismatched.fmt_samples(f, "mismatched")
+    }
+}
+
+/// Number of differences and the first few of them
+#[derive(Debug, Clone, Default)]
+pub struct Differences {
+    pub count: u64,
+    pub samples: Vec<String>,
+}
+
+impl Differences {
+    fn push(&mut self, sample: impl FnOnce() -> String) {
+        self.count += 1;
+        if self.samples.len() < MAX_REPORTED_ROWS {
+            self.samples.push(sample());
+        }
+    }
+
+    fn fmt_samples(&self, f: &mut fmt::Formatter<'_>, kind: &str) -> fmt::Result {
+        for sample in &self.samples {
+            writeln!(f, "  {} {}", kind, sample)?;
+        }
+        if self.count > self.samples.len() as u64 {
+            writeln!(
+                f,
+                "  ... and {} more {}",
+                self.count - self.samples.len() as u64,
+                kind
+            )?;
+        }
+        Ok(())
+    }
+}
+
+struct Verifier<'a> {
+    sqlx_client: &'a SqlxClient,
+    bundle: &'a BundleReader,
+    service_id: ServiceId,
+    service_id_override: Option<ServiceId>,
+    filter: ExportFilter,
+    key: &'a [u8; 32],
+}
+
+impl Verifier<'_> {
+    /// Bundles are exported in key order, so extra database rows are found by merging
+    /// the bundle with pages of database keys instead of remembering every bundle key
+    async fn verify_file<R: VerifiedRow>(&self) -> Result<FileReport> {
+        let mut report = FileReport {
+            file: self.bundle.manifest().file(R::ENTITY),
+            ..Default::default()
+        };
+        let mut existing = ExistingKeys::default();
+
+        let mut rows = Vec::with_capacity(LOOKUP_BATCH_SIZE);
+        let mut last_key = None;
+        for row in self.bundle.entity::<R>()? {
+            let mut row = row?;
+            if let Some(service_id) = self.service_id_override {
+                row.set_service_id(service_id);
+            }
+
+            let key = row.key();
+            if last_key.as_ref().is_some_and(|last| key <= *last) {
+                anyhow::bail!(
+                    "Rows of {} are not in key order, export the bundle again to verify it",
+                    report.file
+                );
+            }
+            last_key = Some(key);
+            rows.push(row);
+
+            if rows.len() >= LOOKUP_BATCH_SIZE {
+                self.compare(&mut report, &mut existing, &rows).await?;
+                rows.clear();
+            }
+        }
+        self.compare(&mut report, &mut existing, &rows).await?;
+        self.skip_existing::<R>(&mut report, &mut existing, None)
+            .await?;
+
+        Ok(report)
+    }
+
+    async fn compare<R: VerifiedRow>(
+        &self,
+        report: &mut FileReport,
+        existing_keys: &mut ExistingKeys<R::Key>,
+        rows: &[R],
+    ) -> Result<()> {
+        if rows.is_empty() {
+            return Ok(());
+        }
+
+        let keys = rows.iter().map(R::key).collect::<Vec<_>>();
+        let mut existing = R::fetch(self.sqlx_client, &keys)
+            .await?
+            .into_iter()
+            .map(|row| (row.key(), row.into_exported(self.key)))
+            .collect::<HashMap<_, _>>();
+
+        for (key, row) in keys.into_iter().zip(rows) {
+            report.checked += 1;
+
+            match existing.remove(&key) {
+                // Decimals compare by value, parquet bundles drop their trailing zeros
+                Some(existing) if existing == *row => {}
+                Some(existing) => {
+                    let fields = different_fields(row, &existing)?;
+                    if !fields.is_empty() {
+                        report
+                            .mismatched
+                            .push(|| format!("{}: {}", key, fields.join(", ")));
+                    }
+                }
+                None => report.missing.push(|| key.to_string()),
+            }
+
+            self.skip_existing::<R>(report, existing_keys, Some(&key))
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Reports database keys before `key` as extra and consumes `key` itself,
+    /// without `key` every remaining database key is extra
+    async fn skip_existing<R: VerifiedRow>(
+        &self,
+        report: &mut FileReport,
+        existing: &mut ExistingKeys<R::Key>,
+        key: Option<&R::Key>,
+    ) -> Result<()> {
+        loop {
+            let next = match existing.page.front() {
+                Some(next) => next,
+                None if existing.done => return Ok(()),
+                None => {
+                    let page = R::keys_after(
+                        self.sqlx_client,
+                        self.service_id,
+                        &self.filter,
+                        existing.after.as_ref(),
+                        LOOKUP_BATCH_SIZE as i64,
+                    )
+                    .await?;
+                    existing.done = page.len() < LOOKUP_BATCH_SIZE;
+                    existing.after = page.last().cloned();
+                    existing.page = page.into();
+                    continue;
+                }
+            };
+
+            match key {
+                Some(key) if next > key => return Ok(()),
+                Some(key) if next == key => {
+                    existing.page.pop_front();
+                    return Ok(());
+                }
+                _ => {
+                    if let Some(extra) = existing.page.pop_front() {
+                        report.extra.push(|| extra.to_string());
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Database keys of the verified range, fetched a page at a time in key order
+struct ExistingKeys<K> {
+    page: VecDeque<K>,
+    /// Last fetched key, the next page starts after it
+    after: Option<K>,
+    done: bool,
+}
+
+impl<K> Default for ExistingKeys<K> {
+    fn default() -> Self {
+        Self {
+            page: VecDeque::new(),
+            after: None,
+            done: false,
+        }
+    }
+}
+
+/// Names of the fields which differ between two rows
+fn different_fields<T: Serialize>(left: &T, right: &T) -> Result<Vec<String>> {
+    let left = serde_json::to_value(left)?;
+    let right = serde_json::to_value(right)?;
+
+    let fields = match (left, right) {
+        (serde_json::Value::Object(left), serde_json::Value::Object(right)) => left
+            .into_iter()
+            .filter(|(field, value)| right.get(field) != Some(value))
+            .map(|(field, _)| field)
+            .collect(),
+        (left, right) if left != right => vec!["<row>".to_owned()],
+        _ => Vec::new(),
+    };
+
+    Ok(fields)
+}
+
+#[async_trait]
+trait VerifiedRow: BundleRow + PartialEq + Sized {
+    /// Ordered as by the database, bundles are exported in this order
+    type Key: Ord + Hash + Clone + fmt::Display + Send + Sync;
+
+    fn key(&self) -> Self::Key;
+
+    /// Applies the service id override the same way import does
+    fn set_service_id(&mut self, _service_id: ServiceId) {}
+
+    /// Converts a database row to the form it has in the bundle
+    fn into_exported(self, _key: &[u8; 32]) -> Self {
+        self
+    }
+
+    async fn fetch(sqlx_client: &SqlxClient, keys: &[Self::Key]) -> crate::Result<Vec<Self>>;
+
+    /// Keys of the database rows passing `filter` after `after`, at most `limit` in key order
+    async fn keys_after(
+        sqlx_client: &SqlxClient,
+        service_id: ServiceId,
+        filter: &ExportFilter,
+        after: Option<&Self::Key>,
+        limit: i64,
+    ) -> crate::Result<Vec<Self::Key>>;
+}
+
+#[async_trait]
+impl VerifiedRow for AddressDb {
+    type Key = Uuid;
+
+    fn key(&self) -> Uuid {
+        self.id
+    }
+
+    fn set_service_id(&mut self, service_id: ServiceId) {
+        self.service_id = service_id;
+    }
+
+    fn into_exported(mut self, key: &[u8; 32]) -> Self {
+        // Undecryptable keys are left as is and reported as mismatched
+        if let Ok(private_key) = decrypt(&self.private_key, key, &self.id) {
+            self.private_key = base64::encode(&*private_key);
+        }
+        self.balance = BigDecimal::from(0);
+        self
+    }
+
+    async fn fetch(sqlx_client: &SqlxClient, keys: &[Uuid]) -> crate::Result<Vec<Self>> {
+        sqlx_client.get_addresses_by_ids(keys).await
+    }
+
+    async fn keys_after(
+        sqlx_client: &SqlxClient,
+        service_id: ServiceId,
+        filter: &ExportFilter,
+        after: Option<&Uuid>,
+        limit: i64,
+    ) -> crate::Result<Vec<Uuid>> {
+        sqlx_client
+            .get_address_ids_after(service_id, filter, after.copied(), limit)
+            .await
+    }
+}
+
+#[async_trait]
+impl VerifiedRow for TransactionDb {
+    type Key = Uuid;
+
+    fn key(&self) -> Uuid {
+        self.id
+    }
+
+    fn set_service_id(&mut self, service_id: ServiceId) {
+        self.service_id = service_id;
+    }
+
+    async fn fetch(sqlx_client: &SqlxClient, keys: &[Uuid]) -> crate::Result<Vec<Self>> {
+        sqlx_client.get_transactions_by_ids(keys).await
+    }
+
+    async fn keys_after(
+        sqlx_client: &SqlxClient,
+        service_id: ServiceId,
+        filter: &ExportFilter,
+        after: Option<&Uuid>,
+        limit: i64,
+    ) -> crate::Result<Vec<Uuid>> {
+        sqlx_client
+            .get_transaction_ids_after(service_id, filter, after.copied(), limit)
+            .await
+    }
+}
+
+#[async_trait]
+impl VerifiedRow for TokenTransactionDb {
+    type Key = Uuid;
+
+    fn key(&self) -> Uuid {
+        self.id
+    }
+
+    fn set_service_id(&mut self, service_id: ServiceId) {
+        self.service_id = service_id;
+    }
+
+    async fn fetch(sqlx_client: &SqlxClient, keys: &[Uuid]) -> crate::Result<Vec<Self>> {
+        sqlx_client.get_token_transactions_by_ids(keys).await
+    }
+
+    async fn keys_after(
+        sqlx_client: &SqlxClient,
+        service_id: ServiceId,
+        filter: &ExportFilter,
+        after: Option<&Uuid>,
+        limit: i64,
+    ) -> crate::Result<Vec<Uuid>> {
+        sqlx_client
+            .get_token_transaction_ids_after(service_id, filter, after.copied(), limit)
+            .await
+    }
+}
+
+#[async_trait]
+impl VerifiedRow for TokenOwnerDb {
+    type Key = String;
+
+    fn key(&self) -> String {
+        self.address.clone()
+    }
+
+    async fn fetch(sqlx_client: &SqlxClient, keys: &[String]) -> crate::Result<Vec<Self>> {
+        sqlx_client.get_token_owners_by_addresses(keys).await
+    }
+
+    async fn keys_after(
+        sqlx_client: &SqlxClient,
+        service_id: ServiceId,
+        filter: &ExportFilter,
+        after: Option<&String>,
+        limit: i64,
+    ) -> crate::Result<Vec<String>> {
+        sqlx_client
+            .get_token_owner_addresses_after(service_id, filter, after.map(String::as_str), limit)
+            .await
+    }
+}
@@ -0,0 +1,54 @@
+use std::str::FromStr;
+
+use ton_api_utility::models::AccountAddress;
+
+const HEX: &str = "fbff00000000000000000000000000000000000000000000000000000000fbff";
+
+fn address(workchain_id: i32) -> AccountAddress {
+    AccountAddress {
+        workchain_id,
+        hex: HEX.to_owned(),
+    }
+}
+
+#[test]
+fn parses_raw_addresses() {
+    assert_eq!(
+        AccountAddress::from_str(&format!("0:{}", HEX)).unwrap(),
+        address(0)
+    );
+    assert_eq!(
+        AccountAddress::from_str(&format!("-1:{}", HEX.to_ascii_uppercase())).unwrap(),
+        address(-1)
+    );
+}
+
+#[test]
+fn parses_base64url_addresses() {
+    assert_eq!(
+        AccountAddress::from_str("EQD7_wAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAD7_5ri").unwrap(),
+        address(0)
+    );
+    assert_eq!(
+        AccountAddress::from_str("Ef_7_wAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAD7_2Wq").unwrap(),
+        address(-1)
+    );
+}
+
+#[test]
+fn parses_standard_base64_addresses() {
+    assert_eq!(
+        AccountAddress::from_str("EQD7/wAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAD7/5ri").unwrap(),
+        address(0)
+    );
+}
+
+#[test]
+fn rejects_hex_without_workchain() {
+    let error = AccountAddress::from_str(HEX).unwrap_err();
+    assert!(
+        error.to_string().contains(&format!("`0:{}`", HEX)),
+        "{}",
+        error
+    );
+}
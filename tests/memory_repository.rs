@@ -0,0 +1,235 @@
+use std::path::{Path, PathBuf};
+
+use bigdecimal::BigDecimal;
+use chrono::{NaiveDate, NaiveDateTime};
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+use ton_api_utility::export::*;
+use ton_api_utility::import::*;
+use ton_api_utility::models::*;
+use ton_api_utility::repository::*;
+use ton_api_utility::sqlx_client::OnConflict;
+use ton_api_utility::utils::encrypt;
+use ton_api_utility::Error;
+
+const KEY: [u8; 32] = [7; 32];
+const HEX: &str = "aa00000000000000000000000000000000000000000000000000000000000001";
+
+fn time(day: u32) -> NaiveDateTime {
+    NaiveDate::from_ymd_opt(2021, 1, day)
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .unwrap()
+}
+
+fn service_id() -> ServiceId {
+    ServiceId::new(Uuid::from_u128(1))
+}
+
+/// One row of every entity, as the database would return it
+fn source_state() -> MemoryState {
+    let service_id = service_id();
+    let address_id = Uuid::from_u128(2);
+    let address = AddressDb {
+        id: address_id,
+        service_id,
+        workchain_id: 0,
+        hex: HEX.to_owned(),
+        base64url: "EQCqAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAB".to_owned(),
+        public_key: "00".repeat(32),
+        private_key: encrypt(&base64::encode([1u8; 32]), &KEY, &address_id).unwrap(),
+        account_type: AccountType::SafeMultisig,
+        custodians: Some(2),
+        confirmations: Some(2),
+        custodians_public_keys: Some(serde_json::json!(["00".repeat(32), "01".repeat(32)])),
+        // Balances are exported as zero
+        balance: BigDecimal::from(0),
+        created_at: time(1),
+        updated_at: time(2),
+    };
+    let transaction = TransactionDb {
+        id: Uuid::from_u128(3),
+        service_id,
+        message_hash: "11".repeat(32),
+        transaction_hash: Some("22".repeat(32)),
+        transaction_lt: Some(BigDecimal::from(100)),
+        transaction_timeout: None,
+        transaction_scan_lt: Some(100),
+        transaction_timestamp: Some(time(2)),
+        sender_workchain_id: None,
+        sender_hex: None,
+        account_workchain_id: 0,
+        account_hex: HEX.to_owned(),
+        messages: Some(serde_json::json!([{ "value": "1000" }])),
+        messages_hash: None,
+        data: None,
+        original_value: Some(BigDecimal::from(1000)),
+        original_outputs: None,
+        value: Some(BigDecimal::from(1000)),
+        fee: Some(BigDecimal::from(10)),
+        balance_change: Some(BigDecimal::from(-1010)),
+        direction: TonTransactionDirection::Send,
+        status: TonTransactionStatus::Done,
+        error: None,
+        aborted: false,
+        bounce: false,
+        created_at: time(1),
+        updated_at: time(3),
+    };
+    let token_owner = TokenOwnerDb {
+        address: "0:".to_owned() + &"bb".repeat(32),
+        owner_account_workchain_id: 0,
+        owner_account_hex: HEX.to_owned(),
+        root_address: "0:".to_owned() + &"cc".repeat(32),
+        code_hash: vec![1, 2, 3],
+        created_at: time(1),
+    };
+    let token_transaction = TokenTransactionDb {
+        id: Uuid::from_u128(4),
+        service_id,
+        transaction_hash: Some("33".repeat(32)),
+        transaction_timestamp: Some(time(2)),
+        message_hash: "44".repeat(32),
+        owner_message_hash: None,
+        account_workchain_id: 0,
+        account_hex: HEX.to_owned(),
+        value: BigDecimal::from(5),
+        root_address: token_owner.root_address.clone(),
+        payload: Some(vec![4, 5, 6]),
+        error: None,
+        block_hash: Some("55".repeat(32)),
+        block_time: Some(1609459200),
+        direction: TonTransactionDirection::Receive,
+        status: TonTokenTransactionStatus::Done,
+        created_at: time(1),
+        updated_at: time(3),
+    };
+
+    MemoryState {
+        addresses: [(address.id, address)].into(),
+        transactions: [(transaction.id, transaction)].into(),
+        token_owners: [(token_owner.address.clone(), token_owner)].into(),
+        token_transactions: [(token_transaction.id, token_transaction)].into(),
+    }
+}
+
+/// Exports the rows of `state` into a bundle inside `dir`
+async fn export(state: MemoryState, dir: &Path) -> PathBuf {
+    let path = dir.join("bundle");
+    run_export(
+        &MemoryRepository::from_state(state),
+        service_id(),
+        path.clone(),
+        Zeroizing::new(KEY),
+        ExportOptions::default(),
+    )
+    .await
+    .unwrap();
+    path
+}
+
+async fn import(
+    target: &MemoryRepository,
+    path: PathBuf,
+    on_conflict: OnConflict,
+) -> ton_api_utility::Result<()> {
+    let options = ImportOptions {
+        on_conflict,
+        ..Default::default()
+    };
+    let report = run_import(target, None, path, Zeroizing::new(KEY), options).await?;
+    assert!(report.warnings.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn round_trip_into_empty_repository() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = export(source_state(), dir.path()).await;
+
+    let target = MemoryRepository::new();
+    import(&target, path, OnConflict::Fail).await.unwrap();
+
+    assert_eq!(target.snapshot(), source_state());
+}
+
+#[tokio::test]
+async fn on_conflict_fail_reports_the_line() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = export(source_state(), dir.path()).await;
+
+    let target = MemoryRepository::from_state(source_state());
+    let error = import(&target, path, OnConflict::Fail).await.unwrap_err();
+
+    match error {
+        Error::DuplicateRow {
+            entity,
+            key,
+            file,
+            line,
+            ..
+        } => {
+            assert_eq!(entity, Entity::Addresses);
+            assert_eq!(key, "id");
+            assert_eq!(file.as_deref(), Some("addresses.jsonl"));
+            assert_eq!(line, Some(1));
+        }
+        error => panic!("Unexpected error {:?}", error),
+    }
+    assert_eq!(target.snapshot(), source_state());
+}
+
+#[tokio::test]
+async fn on_conflict_skip_keeps_existing_rows() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = export(source_state(), dir.path()).await;
+
+    let mut existing = source_state();
+    for transaction in existing.transactions.values_mut() {
+        transaction.status = TonTransactionStatus::New;
+        transaction.updated_at = time(2);
+    }
+    existing.token_owners.clear();
+
+    let target = MemoryRepository::from_state(existing.clone());
+    import(&target, path, OnConflict::Skip).await.unwrap();
+
+    let imported = target.snapshot();
+    assert_eq!(imported.transactions, existing.transactions);
+    assert_eq!(imported.token_owners, source_state().token_owners);
+}
+
+#[tokio::test]
+async fn on_conflict_update_refreshes_older_rows() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = export(source_state(), dir.path()).await;
+
+    let mut existing = source_state();
+    for address in existing.addresses.values_mut() {
+        address.custodians = Some(1);
+        address.confirmations = Some(1);
+        address.custodians_public_keys = Some(serde_json::json!(["00".repeat(32)]));
+        address.balance = BigDecimal::from(42);
+        address.updated_at = time(1);
+    }
+    for transaction in existing.transactions.values_mut() {
+        transaction.status = TonTransactionStatus::New;
+        transaction.updated_at = time(2);
+    }
+    // Newer than the bundle, so it is kept
+    for transaction in existing.token_transactions.values_mut() {
+        transaction.status = TonTokenTransactionStatus::Error;
+        transaction.updated_at = time(4);
+    }
+
+    let target = MemoryRepository::from_state(existing.clone());
+    import(&target, path, OnConflict::Update).await.unwrap();
+
+    let imported = target.snapshot();
+    let mut expected = source_state();
+    for address in expected.addresses.values_mut() {
+        address.balance = BigDecimal::from(42);
+    }
+    expected.token_transactions = existing.token_transactions;
+    assert_eq!(imported, expected);
+}